@@ -0,0 +1,73 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Exponential backoff of the failed reconciles of each AutoVPA, reset once it reconciles successfully.
+pub struct Backoff<K> {
+    failures: Mutex<HashMap<K, u32>>,
+}
+
+impl<K> Default for Backoff<K> {
+    fn default() -> Self {
+        Self { failures: Default::default() }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Backoff<K> {
+    /// Records a failure of `obj` and returns the delay before retrying it: doubling from `base` up to `max`,
+    /// with a random half of it taken off so AutoVPAs failing together don't retry together.
+    pub fn next_delay(&self, obj: &K, base: Duration, max: Duration) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let attempts = failures.entry(obj.clone()).or_default();
+        *attempts = attempts.saturating_add(1);
+        let delay = delay(*attempts, base, max);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    pub fn reset(&self, obj: &K) {
+        self.failures.lock().unwrap().remove(obj);
+    }
+
+    /// The objects whose last reconcile failed, and which are waiting for a retry.
+    pub fn pending(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+}
+
+/// The delay before the `attempts`th retry, without jitter.
+fn delay(attempts: u32, base: Duration, max: Duration) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1))).min(max)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{delay, Backoff};
+    use std::time::Duration;
+
+    const BASE: Duration = Duration::from_secs(5);
+    const MAX: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(Duration::from_secs(5), delay(1, BASE, MAX));
+        assert_eq!(Duration::from_secs(10), delay(2, BASE, MAX));
+        assert_eq!(Duration::from_secs(160), delay(6, BASE, MAX));
+        assert_eq!(Duration::from_secs(300), delay(7, BASE, MAX));
+        assert_eq!(Duration::from_secs(300), delay(u32::MAX, BASE, MAX));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::default();
+        let obj = "AutoVPA/default/nginx".to_string();
+        let first = backoff.next_delay(&obj, BASE, MAX);
+        assert!(first >= Duration::from_millis(2500) && first <= Duration::from_secs(5));
+        let second = backoff.next_delay(&obj, BASE, MAX);
+        assert!(second >= Duration::from_secs(5) && second <= Duration::from_secs(10));
+
+        backoff.reset(&obj);
+        assert!(backoff.next_delay(&obj, BASE, MAX) <= Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::controller::{Error, VerticalPodAutoscalerTemplateSpec};
+use crate::utils;
+use kube::core::GroupVersionKind;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::*;
+
+/// How often the config file is checked for changes. The kubelet takes longer to update a mounted ConfigMap anyway.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Controller-wide configuration, read from the YAML file given with `--config` and reloaded when it changes.
+/// Fields missing from the file keep their default.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Config {
+    /// Kinds AutoVPAs can target on top of the built-in ones, as `group/version/Kind`.
+    pub extra_target_kinds: Vec<String>,
+    /// Namespaces no VPA is generated in, whatever the AutoVPAs select.
+    pub protected_namespaces: Vec<String>,
+    /// VPA template of the AutoVPAs without `vpaTemplate`.
+    pub default_template: Option<VerticalPodAutoscalerTemplateSpec>,
+    /// Seconds between the resyncs of every AutoVPA, which catch drifted VPAs whose events were missed. `0` disables them.
+    pub resync_period: u64,
+    pub rate_limits: RateLimits,
+    /// The built-in kinds and `extra_target_kinds`, the ones AutoVPAs can target. Resolved when the config is validated.
+    #[serde(skip)]
+    pub registered: Vec<GroupVersionKind>,
+}
+
+/// Delays before retrying the failed reconciles of an AutoVPA, doubling from the base delay up to the max one.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Seconds before the first retry.
+    pub retry_base_delay: u64,
+    /// Seconds between two retries at most.
+    pub retry_max_delay: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            extra_target_kinds: vec![],
+            protected_namespaces: vec![],
+            default_template: None,
+            resync_period: 600,
+            rate_limits: RateLimits::default(),
+            registered: builtin_gvks(),
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self { retry_base_delay: 5, retry_max_delay: 300 }
+    }
+}
+
+impl Config {
+    /// Reads and validates the config at `path`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|err| Error::InvalidConfig(format!("{}: {}", path.display(), err)))?;
+        Self::parse(&yaml)
+    }
+
+    fn parse(yaml: &str) -> Result<Self, Error> {
+        // A file without any setting, like an empty ConfigMap key, is the default config.
+        let mut config: Config = serde_yaml::from_str::<Option<Config>>(yaml)?.unwrap_or_default();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&mut self) -> Result<(), Error> {
+        let mut registered = builtin_gvks();
+        for gvk in &self.extra_target_kinds {
+            let gvk = utils::parse_gvk(gvk)?;
+            if !registered.contains(&gvk) {
+                registered.push(gvk);
+            }
+        }
+        self.registered = registered;
+
+        if let Some(template) = &self.default_template {
+            template.validate()?;
+        }
+
+        let RateLimits { retry_base_delay, retry_max_delay } = self.rate_limits;
+        if retry_base_delay == 0 || retry_base_delay > retry_max_delay {
+            return Err(Error::InvalidConfig(format!(
+                "rateLimits.retryBaseDelay must be between 1 and retryMaxDelay ({}), got {}",
+                retry_max_delay, retry_base_delay
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn resync(&self) -> Option<Duration> {
+        (self.resync_period > 0).then(|| Duration::from_secs(self.resync_period))
+    }
+
+    pub fn is_protected(&self, namespace: &str) -> bool {
+        self.protected_namespaces.iter().any(|n| n == namespace)
+    }
+}
+
+impl RateLimits {
+    pub fn base_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_base_delay)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_max_delay)
+    }
+}
+
+/// The kinds every AutoVPA can target.
+fn builtin_gvks() -> Vec<GroupVersionKind> {
+    vec![
+        GroupVersionKind::gvk("apps", "v1", "Deployment"),
+        GroupVersionKind::gvk("apps", "v1", "StatefulSet"),
+        GroupVersionKind::gvk("apps", "v1", "DaemonSet"),
+        GroupVersionKind::gvk("batch", "v1", "Job"),
+        GroupVersionKind::gvk("batch", "v1", "CronJob"),
+    ]
+}
+
+/// Sends the config at `path` to `tx` whenever its content changes.
+/// An invalid config is only logged, the controller keeps running with the previous one.
+pub async fn reload(path: PathBuf, tx: watch::Sender<Arc<Config>>) {
+    let mut last = std::fs::read_to_string(&path).map_err(|err| err.to_string());
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let yaml = tokio::fs::read_to_string(&path).await.map_err(|err| err.to_string());
+        if yaml == last {
+            continue;
+        }
+        last = yaml.clone();
+        let config = yaml
+            .map_err(|err| Error::InvalidConfig(format!("{}: {}", path.display(), err)))
+            .and_then(|yaml| Config::parse(&yaml));
+        match config {
+            Ok(config) => {
+                let changed = tx.send_if_modified(|current| {
+                    let changed = **current != config;
+                    *current = Arc::new(config);
+                    changed
+                });
+                if changed {
+                    info!(path = %path.display(), "config reloaded");
+                }
+            }
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "invalid config, keeping the previous one")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use kube::core::GroupVersionKind;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("# nothing set\n").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.resync(), Some(Duration::from_secs(600)));
+
+        let config = Config::parse(
+            r#"
+extraTargetKinds:
+- argoproj.io/v1alpha1/Rollout
+- apps/v1/Deployment
+protectedNamespaces: [kube-system]
+resyncPeriod: 0
+rateLimits:
+  retryMaxDelay: 60
+defaultTemplate:
+  template:
+    updatePolicy:
+      updateMode: "Off"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.registered.len(), 6);
+        assert_eq!(
+            config.registered.last(),
+            Some(&GroupVersionKind::gvk("argoproj.io", "v1alpha1", "Rollout"))
+        );
+        assert!(config.is_protected("kube-system"));
+        assert!(!config.is_protected("default"));
+        assert_eq!(config.resync(), None);
+        assert_eq!(config.rate_limits.base_delay(), Duration::from_secs(5));
+        assert_eq!(config.rate_limits.max_delay(), Duration::from_secs(60));
+        assert!(config.default_template.is_some());
+
+        assert!(Config::parse("extraTargetKinds: [Rollout]").is_err());
+        assert!(Config::parse("resyncPeriod: soon").is_err());
+        assert!(Config::parse("rateLimits: {retryBaseDelay: 600}").is_err());
+        assert!(Config::parse("protectedNamespace: [kube-system]").is_err());
+        assert!(Config::parse("defaultTemplate: {template: {}, nameTemplate: vpa}").is_err());
+    }
+}
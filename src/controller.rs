@@ -1,53 +1,734 @@
+use crate::backoff::Backoff;
+use crate::config;
+use crate::diagnostics::{Diagnostics, RenderedVPA};
+use crate::events::{EventBatch, EventRecorder};
+use crate::health::Health;
+use crate::http;
+use crate::leader::LeaderElector;
+use crate::metrics::Metrics;
+use crate::overrides;
+#[cfg(feature = "telemetry")]
+use crate::telemetry;
 use crate::utils::{self, convert_label_selector_to_query_string};
 use crate::vpa::VerticalPodAutoscalerTargetRef;
-use futures::StreamExt;
-use kube::api::{Patch, PatchParams};
+use futures::channel::mpsc;
+use futures::{stream, FutureExt, Stream, StreamExt};
+use kube::api::{DeleteParams, Patch, PatchParams};
+use kube::config::{KubeConfigOptions, Kubeconfig};
 use kube::core::{DynamicObject, GroupVersionKind};
 use kube::discovery::ApiResource;
-use kube::runtime::reflector::{ObjectRef, Store};
-use kube::runtime::watcher::Config;
-use kube::runtime::Controller;
-use kube::{Api, Resource, ResourceExt};
-use std::sync::Arc;
+use kube::runtime::reflector::{self, reflector, ObjectRef, Store};
+use kube::runtime::watcher::{self, watcher, Config};
+use kube::runtime::{controller, Controller, WatchStreamExt};
+use kube::{Api, CustomResourceExt, Resource, ResourceExt};
+use regex::Regex;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use std::time::Duration;
 use thiserror::Error;
-use tracing_subscriber::{prelude::*, EnvFilter, Registry};
+use tokio::sync::{watch, Semaphore};
+use tracing_subscriber::{prelude::*, EnvFilter, Layer, Registry};
 
-use crate::vpa::{VerticalPodAutoscaler, VerticalPodAutoscalerSpec};
+use crate::vpa::{
+    VerticalPodAutoscaler, VerticalPodAutoscalerSpec, VerticalPodAutoscalerUpdateMode,
+    VerticalPodAutoscalerUpdatePolicy,
+};
 use k8s_openapi::{
-    apimachinery::pkg::apis::meta::v1::LabelSelector, apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    api::core::v1::Namespace,
+    apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector, ObjectMeta, Time},
+    chrono::Utc,
+    ClusterResourceScope, NamespaceResourceScope,
 };
 use kube::{api::ListParams, runtime::controller::Action, Client, CustomResource};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject, StringValidation};
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::*;
 
+/// Kinds targeted by an AutoVPA without `targetKinds`.
+const DEFAULT_TARGET_KINDS: [&str; 5] =
+    ["Deployment", "StatefulSet", "DaemonSet", "Job", "CronJob"];
+/// Marks a hand-written VPA taken over through `adoptionPolicy: Adopt`, so it keeps its name.
+const ADOPTED_ANNOTATION: &str = "autovpa.dev/adopted";
+/// Hash of the spec a generated VPA was last applied with, a different live spec means it was edited since.
+const SPEC_HASH_ANNOTATION: &str = "autovpa.dev/spec-hash";
+/// Seconds the watches may only fail for before the liveness probe fails.
+const STALL_THRESHOLD_ENV: &str = "AUTOVPA_STALL_THRESHOLD";
+const DEFAULT_STALL_THRESHOLD: Duration = Duration::from_secs(300);
+
 struct Ctx {
     client: Client,
-    gvks: Vec<GroupVersionKind>,
+    targets: TargetIndex,
+    autovpas: Store<AutoVPA>,
+    cluster_autovpas: Store<ClusterAutoVPA>,
+    namespaces: Store<Namespace>,
+    recorder: EventRecorder,
+    backoff: Backoff<AutoVPAKey>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    diagnostics: Arc<Diagnostics>,
+    /// Bounds the reconciles running at once, if `concurrency` is set.
+    permits: Option<Semaphore>,
+    settings: Settings,
+    config: watch::Receiver<Arc<config::Config>>,
 }
-// Define the AutoVPA CRD struct
-#[derive(CustomResource, Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-#[kube(group = "autovpa.dev", version = "v1", kind = "AutoVPA")]
-#[kube(status = "AutoVPAStatus")]
-#[kube(printcolumn = r#"{"name":"matched", "jsonPath": ".status.matched", "type": "integer"}"#)]
+
+/// State kept across the restarts of the controllers.
+#[derive(Clone)]
+struct Shared {
+    targets: TargetIndex,
+    autovpas: Store<AutoVPA>,
+    cluster_autovpas: Store<ClusterAutoVPA>,
+    namespaces: Store<Namespace>,
+    /// The objects touched by the watches of the stores, shared by both controllers and by `watch_target_kinds`.
+    autovpa_events: Fanout<AutoVPA>,
+    cluster_autovpa_events: Fanout<ClusterAutoVPA>,
+    namespace_events: Fanout<Namespace>,
+    recorder: EventRecorder,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    diagnostics: Arc<Diagnostics>,
+    config: watch::Receiver<Arc<config::Config>>,
+}
+
+impl Ctx {
+    /// The AutoVPAs of both kinds, which compete for the same targets.
+    fn candidates(&self) -> Vec<Arc<dyn AutoVPAResource>> {
+        let autovpas = self.autovpas.state().into_iter().map(|g| g as Arc<dyn AutoVPAResource>);
+        let cluster_autovpas =
+            self.cluster_autovpas.state().into_iter().map(|g| g as Arc<dyn AutoVPAResource>);
+        autovpas.chain(cluster_autovpas).collect()
+    }
+
+    /// The current config, which may be reloaded between two reconciles.
+    fn config(&self) -> Arc<config::Config> {
+        self.config.borrow().clone()
+    }
+}
+
+/// Controller-wide settings, fixed for the lifetime of the controller unlike the [`config::Config`].
+#[derive(Clone, Debug, Default)]
+struct Settings {
+    /// Dry-runs every AutoVPA, as if they all had `dryRun: true`.
+    dry_run: bool,
+    /// Neither publishes events nor patches the status of the AutoVPAs, so their changes are only planned.
+    read_only: bool,
+    /// Field manager of the applied VPAs.
+    field_manager: String,
+    /// Reconciles running at once at most, unbounded if `0`.
+    concurrency: usize,
+    /// Namespaces the controller watches, all of them if empty. ClusterAutoVPAs are only handled along with
+    /// every namespace, as they select workloads across namespaces.
+    namespaces: Vec<String>,
+}
+
+impl Settings {
+    /// Whether every namespace is watched, and the cluster-scoped objects along with them.
+    fn cluster_wide(&self) -> bool {
+        self.namespaces.is_empty()
+    }
+
+    /// The Apis of the `K` objects of the watched namespaces.
+    fn apis<K>(&self, client: &Client) -> Vec<Api<K>>
+    where
+        K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>,
+    {
+        if self.cluster_wide() {
+            return vec![Api::all(client.clone())];
+        }
+        self.namespaces.iter().map(|ns| Api::namespaced(client.clone(), ns)).collect()
+    }
+
+    /// The Apis of the `api_resource` objects of the watched namespaces.
+    fn dyn_apis(&self, client: &Client, api_resource: &ApiResource) -> Vec<Api<DynamicObject>> {
+        if self.cluster_wide() {
+            return vec![Api::all_with(client.clone(), api_resource)];
+        }
+        let namespaced = |ns: &String| Api::namespaced_with(client.clone(), ns, api_resource);
+        self.namespaces.iter().map(namespaced).collect()
+    }
+
+    /// The Api of the cluster-scoped `K` objects, none unless every namespace is watched.
+    fn cluster_apis<K>(&self, client: &Client) -> Vec<Api<K>>
+    where
+        K: Resource<Scope = ClusterResourceScope, DynamicType = ()>,
+    {
+        if self.cluster_wide() {
+            vec![Api::all(client.clone())]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// Options of the controller, set on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Kubeconfig to connect with, rather than the in-cluster config or the default kubeconfig.
+    pub kubeconfig: Option<PathBuf>,
+    /// Context of the kubeconfig to connect with, rather than its current context.
+    pub context: Option<String>,
+    /// Config file of the controller, reloaded when it changes. The default config is used without one.
+    pub config: Option<PathBuf>,
+    /// Dry-runs every AutoVPA, as if they all had `dryRun: true`.
+    pub dry_run: bool,
+    /// Field manager of the applied VPAs.
+    pub field_manager: String,
+    /// Reconciles running at once at most, unbounded if `0`.
+    pub concurrency: usize,
+    /// Namespaces to watch, all of them if empty.
+    pub watch_namespaces: Vec<String>,
+    /// Address the metrics, the probes and the diagnostics are served on.
+    pub metrics_address: String,
+}
+
+impl Options {
+    async fn client(&self) -> anyhow::Result<Client> {
+        let options = KubeConfigOptions { context: self.context.clone(), ..Default::default() };
+        let config = match &self.kubeconfig {
+            Some(path) => {
+                kube::Config::from_custom_kubeconfig(Kubeconfig::read_from(path)?, &options).await?
+            }
+            None if self.context.is_some() => kube::Config::from_kubeconfig(&options).await?,
+            None => kube::Config::infer().await?,
+        };
+        Ok(Client::try_from(config)?)
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            dry_run: self.dry_run,
+            read_only: false,
+            field_manager: self.field_manager.clone(),
+            concurrency: self.concurrency,
+            namespaces: self
+                .watch_namespaces
+                .iter()
+                .map(|ns| ns.trim().to_string())
+                .filter(|ns| !ns.is_empty())
+                .collect(),
+        }
+    }
+
+    fn config(&self) -> Result<config::Config> {
+        match &self.config {
+            Some(path) => config::Config::load(path),
+            None => Ok(config::Config::default()),
+        }
+    }
+}
+
+/// Identifies a workload a VPA is generated for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct TargetKey {
+    kind: String,
+    namespace: String,
+    name: String,
+}
+
+/// Targets each AutoVPA generated a VPA for during its last reconcile.
+/// The mappers use it to wake an AutoVPA up when one of its targets stops matching, so the stale VPA gets pruned.
+type TargetIndex = Arc<RwLock<HashMap<AutoVPAKey, HashSet<TargetKey>>>>;
+
+/// Identifies an AutoVPA of either kind, written `AutoVPA/<namespace>/<name>` or `ClusterAutoVPA/<name>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct AutoVPAKey {
+    kind: String,
+    namespace: Option<String>,
+    name: String,
+}
+
+impl AutoVPAKey {
+    fn from_obj<K: Resource<DynamicType = ()>>(obj: &K) -> Self {
+        Self { kind: K::kind(&()).to_string(), namespace: obj.namespace(), name: obj.name_any() }
+    }
+
+    fn from_ref<K: Resource<DynamicType = ()>>(obj_ref: &ObjectRef<K>) -> Self {
+        Self {
+            kind: K::kind(&()).to_string(),
+            namespace: obj_ref.namespace.clone(),
+            name: obj_ref.name.clone(),
+        }
+    }
+
+    fn parse(key: &str) -> Option<Self> {
+        let parts: Vec<&str> = key.split('/').collect();
+        let (kind, namespace, name) = match parts[..] {
+            [kind, name] => (kind, None, name),
+            [kind, namespace, name] => (kind, Some(namespace.to_string()), name),
+            _ => return None,
+        };
+        Some(Self { kind: kind.to_string(), namespace, name: name.to_string() })
+    }
+
+    /// A reference to the AutoVPA, if it is of kind `K`.
+    fn object_ref<K: Resource<DynamicType = ()>>(&self) -> Option<ObjectRef<K>> {
+        if self.kind != K::kind(&()) {
+            return None;
+        }
+        let obj_ref = ObjectRef::new(&self.name);
+        Some(match &self.namespace {
+            Some(namespace) => obj_ref.within(namespace),
+            None => obj_ref,
+        })
+    }
+}
+
+impl std::fmt::Display for AutoVPAKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.namespace {
+            Some(namespace) => write!(f, "{}/{}/{}", self.kind, namespace, self.name),
+            None => write!(f, "{}/{}", self.kind, self.name),
+        }
+    }
+}
+
+/// What the reconcile needs from the two AutoVPA kinds: the namespaced `AutoVPA`, which only selects workloads of
+/// its own namespace, and the cluster-scoped `ClusterAutoVPA`. Both share the spec and the status.
+trait AutoVPAResource: Send + Sync {
+    fn spec(&self) -> &AutoVPASpec;
+    fn status(&self) -> Option<&AutoVPAStatus>;
+    fn object_meta(&self) -> &ObjectMeta;
+    fn key(&self) -> AutoVPAKey;
+    /// The namespace the targets are restricted to, `None` for a ClusterAutoVPA.
+    fn own_namespace(&self) -> Option<&str>;
+
+    /// The Api of the AutoVPAs of this kind in `namespace`.
+    fn api(client: Client, namespace: Option<&str>) -> Api<Self>
+    where
+        Self: Sized;
+
+    fn namespaced() -> bool
+    where
+        Self: Sized;
+}
+
+impl AutoVPAResource for AutoVPA {
+    fn spec(&self) -> &AutoVPASpec {
+        &self.spec
+    }
+
+    fn status(&self) -> Option<&AutoVPAStatus> {
+        self.status.as_ref()
+    }
+
+    fn object_meta(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn key(&self) -> AutoVPAKey {
+        AutoVPAKey::from_obj(self)
+    }
+
+    fn own_namespace(&self) -> Option<&str> {
+        Some(self.metadata.namespace.as_deref().unwrap_or_default())
+    }
+
+    fn api(client: Client, namespace: Option<&str>) -> Api<Self> {
+        Api::namespaced(client, namespace.unwrap_or_default())
+    }
+
+    fn namespaced() -> bool {
+        true
+    }
+}
+
+impl AutoVPAResource for ClusterAutoVPA {
+    fn spec(&self) -> &AutoVPASpec {
+        &self.spec
+    }
+
+    fn status(&self) -> Option<&AutoVPAStatus> {
+        self.status.as_ref()
+    }
+
+    fn object_meta(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn key(&self) -> AutoVPAKey {
+        AutoVPAKey::from_obj(self)
+    }
+
+    fn own_namespace(&self) -> Option<&str> {
+        None
+    }
+
+    fn api(client: Client, _namespace: Option<&str>) -> Api<Self> {
+        Api::all(client)
+    }
+
+    fn namespaced() -> bool {
+        false
+    }
+}
+
+/// An AutoVPA kind a controller runs for.
+trait AutoVPAKind:
+    AutoVPAResource
+    + Resource<DynamicType = ()>
+    + Clone
+    + std::fmt::Debug
+    + DeserializeOwned
+    + Serialize
+    + 'static
+{
+}
+
+impl<K> AutoVPAKind for K where
+    K: AutoVPAResource
+        + Resource<DynamicType = ()>
+        + Clone
+        + std::fmt::Debug
+        + DeserializeOwned
+        + Serialize
+        + 'static
+{
+}
+
+/// The workloads selected by an AutoVPA or a ClusterAutoVPA, and the VPA generated for each of them.
+/// An AutoVPA only selects workloads of its own namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AutoVPASpec {
-    namespace_selector: Option<Vec<String>>,
+    /// Names of the namespaces to select targets from. All namespaces when not set. ClusterAutoVPA only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace_names: Option<Vec<String>>,
+    /// Selects the namespaces to select targets from by their labels. All namespaces when not set. ClusterAutoVPA only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace_selector: Option<LabelSelector>,
     object_selector: Option<LabelSelector>,
-    vpa_template: VerticalPodAutoscalerTemplateSpec,
+    /// Targets matching this selector get no VPA, even if selected by `objectSelector`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exclude_selector: Option<LabelSelector>,
+    /// Selects the namespaces to select targets from by name patterns, on top of the other namespace selectors.
+    /// ClusterAutoVPA only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace_patterns: Option<NamePatterns>,
+    /// Selects targets by name patterns, on top of `objectSelector`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_patterns: Option<NamePatterns>,
+    /// Kinds of the targets, among the ones registered with the controller.
+    /// Defaults to `Deployment`, `StatefulSet`, `DaemonSet`, `Job` and `CronJob`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_kinds: Option<Vec<String>>,
+    /// Defaults to the `defaultTemplate` of the controller config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vpa_template: Option<VerticalPodAutoscalerTemplateSpec>,
+    /// What to do with a generated VPA whose target no longer matches. Defaults to `Delete`.
+    #[serde(default)]
+    prune_policy: PrunePolicy,
+    /// Decides which AutoVPA generates the VPA when several of them select the same workload.
+    /// The highest priority wins; ties go to the oldest AutoVPA.
+    #[serde(default)]
+    priority: i32,
+    /// What to do when a VPA not generated by an AutoVPA already exists for a target. Defaults to `Skip`.
+    #[serde(default)]
+    adoption_policy: AdoptionPolicy,
+    /// What to do when a generated VPA was edited outside of the AutoVPA. Defaults to `Revert`.
+    #[serde(default)]
+    drift_policy: DriftPolicy,
+    /// Match and render the VPAs without writing them, the planned changes are listed in `status.plan`.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Selects workloads of its own namespace and generates their VPAs. Its spec is the one of a ClusterAutoVPA,
+/// whose namespace selection fields the API server rejects.
+#[derive(CustomResource, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[kube(group = "autovpa.dev", version = "v1", kind = "AutoVPA", namespaced)]
+#[kube(status = "AutoVPAStatus")]
+#[kube(
+    printcolumn = r#"{"name":"ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}"#
+)]
+#[kube(printcolumn = r#"{"name":"matched", "jsonPath": ".status.matched", "type": "integer"}"#)]
+#[kube(printcolumn = r#"{"name":"applied", "jsonPath": ".status.applied", "type": "integer"}"#)]
+#[kube(printcolumn = r#"{"name":"failed", "jsonPath": ".status.failed", "type": "integer"}"#)]
+#[kube(printcolumn = r#"{"name":"excluded", "jsonPath": ".status.excluded", "type": "integer"}"#)]
+#[serde(transparent)]
+pub struct NamespacedAutoVPASpec(AutoVPASpec);
+
+impl JsonSchema for NamespacedAutoVPASpec {
+    fn schema_name() -> String {
+        "NamespacedAutoVPASpec".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let mut schema = AutoVPASpec::json_schema(gen).into_object();
+        let validation = serde_json::json!({
+            "rule": "!has(self.namespaceNames) && !has(self.namespaceSelector) && !has(self.namespacePatterns)",
+            "message": "an AutoVPA only selects its own namespace, use a ClusterAutoVPA to select namespaces",
+        });
+        schema.extensions.insert("x-kubernetes-validations".to_string(), vec![validation].into());
+        schema.into()
+    }
+}
+
+impl std::ops::Deref for NamespacedAutoVPASpec {
+    type Target = AutoVPASpec;
+
+    fn deref(&self) -> &AutoVPASpec {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for NamespacedAutoVPASpec {
+    fn deref_mut(&mut self) -> &mut AutoVPASpec {
+        &mut self.0
+    }
+}
+
+/// Selects workloads across namespaces and generates their VPAs. Its spec is the one of an AutoVPA.
+#[derive(CustomResource, Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(group = "autovpa.dev", version = "v1", kind = "ClusterAutoVPA")]
+#[kube(status = "AutoVPAStatus")]
+#[kube(
+    printcolumn = r#"{"name":"ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}"#
+)]
+#[kube(printcolumn = r#"{"name":"matched", "jsonPath": ".status.matched", "type": "integer"}"#)]
+#[kube(printcolumn = r#"{"name":"applied", "jsonPath": ".status.applied", "type": "integer"}"#)]
+#[kube(printcolumn = r#"{"name":"failed", "jsonPath": ".status.failed", "type": "integer"}"#)]
+#[kube(printcolumn = r#"{"name":"excluded", "jsonPath": ".status.excluded", "type": "integer"}"#)]
+#[serde(transparent)]
+pub struct ClusterAutoVPASpec(AutoVPASpec);
+
+impl std::ops::Deref for ClusterAutoVPASpec {
+    type Target = AutoVPASpec;
+
+    fn deref(&self) -> &AutoVPASpec {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for ClusterAutoVPASpec {
+    fn deref_mut(&mut self) -> &mut AutoVPASpec {
+        &mut self.0
+    }
+}
+
+/// A name matches when it matches any `include` pattern, or `include` is empty, and none of the `exclude` patterns.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct NamePatterns {
+    #[serde(default)]
+    include: Vec<NamePattern>,
+    #[serde(default)]
+    exclude: Vec<NamePattern>,
+}
+
+/// A glob like `team-*`, where `*` matches any sequence of characters and `?` a single one,
+/// or a regular expression prefixed with `regex:` like `regex:^api-(v1|v2)$`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(transparent)]
+pub struct NamePattern {
+    pattern: String,
+    /// Compiled on first use and kept along with the object, as the mappers match every event against the patterns.
+    #[serde(skip)]
+    compiled: OnceLock<Option<Regex>>,
+}
+
+impl PartialEq for NamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl NamePattern {
+    /// The compiled pattern, `None` if it is invalid.
+    fn regex(&self) -> Option<&Regex> {
+        self.compiled.get_or_init(|| utils::compile_pattern(&self.pattern).ok()).as_ref()
+    }
+}
+
+// schemars can't put validations on the items of a list, so the pattern is spelled out here.
+impl JsonSchema for NamePattern {
+    fn schema_name() -> String {
+        "NamePattern".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(r"^(regex:.+|[a-z0-9.*?-]+)$".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl NamePatterns {
+    /// Reports the first invalid pattern.
+    fn validate(&self) -> Result<()> {
+        self.include
+            .iter()
+            .chain(&self.exclude)
+            .try_for_each(|p| utils::compile_pattern(&p.pattern).map(|_| ()))
+    }
+
+    /// Whether `name` is selected. Invalid patterns match nothing, they are reported by `validate`.
+    fn matches(&self, name: &str) -> bool {
+        let is_match = |p: &NamePattern| p.regex().is_some_and(|re| re.is_match(name));
+        (self.include.is_empty() || self.include.iter().any(is_match))
+            && !self.exclude.iter().any(is_match)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub enum PrunePolicy {
+    /// Delete the VPA.
+    #[default]
+    Delete,
+    /// Keep the VPA and its recommendations, but stop it from evicting pods by setting `updateMode: Off`.
+    SetOff,
+    /// Keep the VPA as it is and drop the AutoVPA owner reference, so it is no longer managed.
+    Orphan,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub enum AdoptionPolicy {
+    /// Leave the existing VPA alone and don't generate one for the target.
+    #[default]
+    Skip,
+    /// Take the existing VPA over: it gets the AutoVPA owner reference and the template spec.
+    /// Several VPAs for the same target are left alone, as with `Skip`.
+    Adopt,
+    /// Delete the existing VPA and generate a new one.
+    Replace,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub enum DriftPolicy {
+    /// Apply the template over the edits.
+    #[default]
+    Revert,
+    /// Keep the edits and report the VPA as drifted.
+    Report,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct AutoVPAStatus {
+    /// Targets this AutoVPA generates a VPA for, whether it was applied, failed, skipped or drifted.
+    #[serde(default)]
     matched: i32,
+    /// Targets whose VPA was applied.
+    #[serde(default)]
+    applied: i32,
+    /// Targets whose VPA was only rendered and dry-run applied, because of `dryRun`.
+    #[serde(default)]
+    planned: i32,
+    /// Targets whose VPA could not be applied, e.g. because of invalid `autovpa.dev/*` annotations.
+    #[serde(default)]
+    failed: i32,
+    /// Targets no VPA is generated for because of `adoptionPolicy`.
+    #[serde(default)]
+    skipped: i32,
+    /// Targets whose VPA was edited outside of the AutoVPA and kept as it is because of `driftPolicy: Report`.
+    #[serde(default)]
+    drifted: i32,
+    /// Selected targets left out by `excludeSelector` or the `autovpa.dev/ignore` annotation.
+    #[serde(default)]
+    excluded: i32,
+    /// Targets also selected by other AutoVPAs, with the AutoVPA that generates their VPA.
+    #[serde(default)]
+    conflicted: Vec<ConflictedTarget>,
+    /// Per-target results, failed and skipped ones first, bounded to 100 entries.
+    #[serde(default)]
+    targets: Vec<TargetResult>,
+    /// Changes to the VPAs a dry run would make, bounded to 100 entries.
+    #[serde(default)]
+    plan: Vec<PlannedChange>,
+    /// `Ready`, `Degraded` and `InvalidSelector` conditions.
+    #[serde(default)]
+    conditions: Vec<Condition>,
+    /// The `.metadata.generation` this status was computed for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    observed_generation: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_reconcile_time: Option<Time>,
+}
+
+/// Bounds `status.targets`, so the status of an AutoVPA selecting thousands of workloads stays small.
+const MAX_TARGET_RESULTS: usize = 100;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ConflictedTarget {
+    kind: String,
+    namespace: String,
+    name: String,
+    winner: String,
+    losers: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetResult {
+    kind: String,
+    namespace: String,
+    name: String,
+    vpa_name: String,
+    result: TargetOutcome,
+    /// Why the VPA was not applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedChange {
+    action: PlannedAction,
+    namespace: String,
+    vpa_name: String,
+    /// The targeted workload, as `Kind/name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    /// The VPA spec a create or update would apply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spec: Option<VerticalPodAutoscalerSpec>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum PlannedAction {
+    Create,
+    Update,
+    Delete,
+    SetOff,
+    Orphan,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, JsonSchema)]
+pub enum TargetOutcome {
+    // Declared in the order `status.targets` lists them.
+    Failed,
+    Drifted,
+    Skipped,
+    Applied,
+    Planned,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct VerticalPodAutoscalerTemplateSpec {
     /// Standard object's metadata. More info: https://git.k8s.io/community/contributors/devel/sig-architecture/api-conventions.md#metadata
     metadata: Option<ObjectMeta>,
     template: VerticalPodAutoscalerSpec,
+    /// Name of the generated VPAs, with `{kind}`, `{namespace}` and `{name}` placeholders for the target.
+    /// Must contain `{name}`. Defaults to `{name}-{kind}-vpa`. Names longer than 253 characters are truncated with a hash suffix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_template: Option<String>,
+}
+
+const DEFAULT_NAME_TEMPLATE: &str = "{name}-{kind}-vpa";
+
+impl VerticalPodAutoscalerTemplateSpec {
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.name_template.as_deref().map_or(Ok(()), utils::validate_name_template)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -58,6 +739,42 @@ pub enum Error {
     #[error("Failed to get owner ref")]
     InvalidOwnerRef(),
 
+    #[error("{0} is only supported by ClusterAutoVPA")]
+    NamespacedSelection(&'static str),
+
+    #[error("Kind is not registered with the controller: {0}")]
+    UnknownKind(String),
+
+    #[error("Kind is invalid: {0}")]
+    InvalidKind(String),
+
+    #[error("Name template is invalid: {0}")]
+    InvalidNameTemplate(String),
+
+    #[error("Pattern is invalid: {0}")]
+    InvalidPattern(String),
+
+    #[error("Invalid override annotations: {0}")]
+    InvalidOverride(String),
+
+    #[error("Config is invalid: {0}")]
+    InvalidConfig(String),
+
+    #[error("vpaTemplate is not set and the controller config has no defaultTemplate")]
+    MissingTemplate,
+
+    #[error("Stall threshold is invalid: {0}")]
+    InvalidStallThreshold(String),
+
+    #[error("Leader election is misconfigured: {0}")]
+    InvalidLeaderElection(String),
+
+    #[error("Log format is invalid, expected compact, pretty or json: {0}")]
+    InvalidLogFormat(String),
+
+    #[error("Failed to reconcile {} targets: {}", .0.len(), .0.join("; "))]
+    TargetsFailed(Vec<String>),
+
     #[error("MissingObjectKey: {0}")]
     MissingObjectKey(&'static str),
 
@@ -77,221 +794,1921 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub fn init_logging() {
-    let logger = tracing_subscriber::fmt::layer().compact();
-    let env_filter =
-        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")).unwrap();
-    let collector = Registry::default().with(logger).with(env_filter);
-    // Initialize tracing
-    tracing::subscriber::set_global_default(collector).unwrap();
+impl Error {
+    /// The reason of the conditions reporting the error.
+    fn reason(&self) -> &'static str {
+        match self {
+            Error::InvalidLabelSelector(_) => "InvalidLabelSelector",
+            Error::InvalidOwnerRef() => "InvalidOwnerRef",
+            Error::NamespacedSelection(_) => "NamespacedSelection",
+            Error::UnknownKind(_) => "UnknownKind",
+            Error::InvalidKind(_) => "InvalidKind",
+            Error::InvalidNameTemplate(_) => "InvalidNameTemplate",
+            Error::InvalidPattern(_) => "InvalidPattern",
+            Error::InvalidOverride(_) => "InvalidOverride",
+            Error::InvalidConfig(_) => "InvalidConfig",
+            Error::MissingTemplate => "MissingTemplate",
+            Error::InvalidStallThreshold(_) => "InvalidStallThreshold",
+            Error::InvalidLeaderElection(_) => "InvalidLeaderElection",
+            Error::InvalidLogFormat(_) => "InvalidLogFormat",
+            Error::TargetsFailed(_) => "TargetsFailed",
+            Error::MissingObjectKey(_) => "MissingObjectKey",
+            Error::KubeError { .. } => "KubeError",
+            Error::SerdeError { .. } => "SerdeError",
+        }
+    }
+}
+
+/// Format of the logs. Whatever the format, the controller logs the same fields: `autovpa` and the
+/// `target_kind`, `target_namespace` and `target_name` on the spans of a reconcile and of each of its targets,
+/// `vpa_namespace`, `vpa_name` and `error` on the events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    /// A JSON object per line, with the fields of the event at the top level, the ones of its span under `span`
+    /// and the ones of every enclosing span under `spans`.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "compact" => Ok(Self::Compact),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::InvalidLogFormat(format.to_string())),
+        }
+    }
+}
+
+/// Logs to stderr, which keeps stdout to the output of the subcommands, like the YAML of `plan`.
+/// Fails on an invalid `OTEL_*` configuration, rather than running without exporting the spans.
+pub fn init_logging(format: LogFormat) -> anyhow::Result<()> {
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let logger: Box<dyn Layer<Registry> + Send + Sync> = match format {
+        LogFormat::Compact => Box::new(fmt.compact()),
+        LogFormat::Pretty => Box::new(fmt.pretty()),
+        LogFormat::Json => Box::new(fmt.json().flatten_event(true)),
+    };
+    let env_filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")).unwrap();
+    let collector = Registry::default().with(logger).with(env_filter);
+    // Spans are exported when built with the telemetry feature and an OTLP endpoint is configured.
+    #[cfg(feature = "telemetry")]
+    let collector = collector.with(
+        telemetry::layer().map_err(|e| anyhow::anyhow!("invalid OTEL_* configuration: {}", e))?,
+    );
+    // Initialize tracing
+    tracing::subscriber::set_global_default(collector)?;
+    Ok(())
+}
+
+/// The CRDs of AutoVPA and ClusterAutoVPA, as YAML documents.
+pub fn crds() -> String {
+    format!(
+        "{}---\n{}",
+        serde_yaml::to_string(&AutoVPA::crd()).unwrap(),
+        serde_yaml::to_string(&ClusterAutoVPA::crd()).unwrap()
+    )
+}
+
+/// Runs the controller.
+pub async fn run(options: &Options) -> anyhow::Result<()> {
+    let settings = options.settings();
+    let (config_tx, config) = watch::channel(Arc::new(options.config()?));
+    if let Some(path) = &options.config {
+        tokio::spawn(config::reload(path.clone(), config_tx));
+    }
+
+    let client = options.client().await?;
+
+    let metrics = Arc::new(Metrics::default());
+    let health = Arc::new(Health::new(stall_threshold()?));
+    let diagnostics = Arc::new(Diagnostics::default());
+    let server = http::server(
+        &options.metrics_address,
+        metrics.clone(),
+        health.clone(),
+        diagnostics.clone(),
+    )?;
+    let server_handle = server.handle();
+    tokio::spawn(server);
+
+    let gen_apis: Vec<Api<AutoVPA>> = settings.apis(&client);
+    let cluster_gen_apis: Vec<Api<ClusterAutoVPA>> = settings.cluster_apis(&client);
+    let vpa_api = settings.apis::<VerticalPodAutoscaler>(&client).remove(0);
+
+    // In sceniro of oam controlled contrllers, there is a oam.dev.namespace label in the generated deployment | statefulset...
+    let lp = ListParams::default().limit(1);
+    let mut crds_checked = gen_apis[0].list(&lp).await.map(|_| ());
+    for api in &cluster_gen_apis {
+        crds_checked = crds_checked.and(api.list(&lp).await.map(|_| ()));
+    }
+    if let Err(e) = crds_checked {
+        error!(error = %e, "autovpa crds are not querable, are the crds installed?");
+        info!("Installation: autoscaler-genie crd | kubectl apply -f -");
+        std::process::exit(1);
+    }
+
+    if let Err(e) = vpa_api.list(&ListParams::default().limit(1)).await {
+        error!(error = %e, "vpa crd is not querable, is the crd installed?");
+        std::process::exit(1);
+    }
+
+    let mut autovpas = Vec::new();
+    for api in &gen_apis {
+        autovpas.extend(api.list(&ListParams::default()).await?.items);
+    }
+    let mut cluster_autovpas = Vec::new();
+    for api in &cluster_gen_apis {
+        cluster_autovpas.extend(api.list(&ListParams::default()).await?.items);
+    }
+    // AutoVPAs without a namespace come from the cluster-scoped CRD of older releases.
+    let cluster_scoped: Vec<String> =
+        autovpas.iter().filter(|g| g.namespace().is_none()).map(|g| g.name_any()).collect();
+    if !cluster_scoped.is_empty() {
+        error!(
+            autovpas = ?cluster_scoped,
+            "the installed AutoVPA crd is cluster-scoped, migrate the AutoVPAs to ClusterAutoVPAs first"
+        );
+        info!("Migration: see \"Migrating from cluster-scoped AutoVPAs\" in the README");
+        std::process::exit(1);
+    }
+    health.crds_checked();
+
+    // Only one replica runs the controller, the other ones wait to take over.
+    let leader = LeaderElector::from_env(client.clone(), metrics.clone())?;
+    if let Some(leader) = &leader {
+        tokio::select! {
+            _ = leader.acquire() => (),
+            _ = shutdown_signal() => return Ok(()),
+        }
+    }
+
+    // The AutoVPAs, ClusterAutoVPAs and namespaces are watched once, for both controllers and across their restarts.
+    // ClusterAutoVPAs and namespaces are cluster-scoped, their stores stay empty unless every namespace is watched.
+    health.syncing(match settings.cluster_wide() {
+        true => &["autovpas", "clusterautovpas", "namespaces"],
+        false => &["autovpas"],
+    });
+    let (autovpa_store, stream) = synced_reflector(gen_apis, &health, &metrics, "autovpas");
+    let (autovpa_events, autovpa_forward) = Fanout::new(stream);
+    let (cluster_autovpa_store, stream) =
+        synced_reflector(cluster_gen_apis, &health, &metrics, "clusterautovpas");
+    let (cluster_autovpa_events, cluster_autovpa_forward) = Fanout::new(stream);
+    let (namespaces, stream) = synced_reflector(
+        settings.cluster_apis::<Namespace>(&client),
+        &health,
+        &metrics,
+        "namespaces",
+    );
+    let (namespace_events, namespace_forward) = Fanout::new(stream);
+    diagnostics.set_stores(autovpa_store.clone(), cluster_autovpa_store.clone());
+    let shared = Shared {
+        targets: TargetIndex::default(),
+        autovpas: autovpa_store,
+        cluster_autovpas: cluster_autovpa_store,
+        namespaces,
+        autovpa_events,
+        cluster_autovpa_events,
+        namespace_events,
+        recorder: EventRecorder::new(client.clone()),
+        metrics: metrics.clone(),
+        health,
+        diagnostics,
+        config: config.clone(),
+    };
+
+    // Only the kinds targeted by some AutoVPA are watched, so the controller restarts when that set changes.
+    let all = autovpas.iter().map(|g| g as &dyn AutoVPAResource);
+    let all = all.chain(cluster_autovpas.iter().map(|g| g as &dyn AutoVPAResource));
+    let registered = config.borrow().registered.clone();
+    let (watched_tx, mut watched_rx) = watch::channel(watched_gvks(all, &registered));
+    tokio::spawn(watch_target_kinds(&shared, watched_tx));
+    tokio::spawn(autovpa_forward);
+    tokio::spawn(cluster_autovpa_forward);
+    tokio::spawn(namespace_forward);
+
+    let controllers = async move {
+        loop {
+            let watched = watched_rx.borrow_and_update().clone();
+            info!(kinds = ?watched.iter().map(|g| &g.kind).collect::<Vec<_>>(), "watching target kinds");
+            let mut changed = watched_rx.clone();
+            let shutdown = async move {
+                if changed.changed().await.is_err() {
+                    futures::future::pending::<()>().await;
+                }
+            };
+            run_controller(client.clone(), &watched, shared.clone(), settings.clone(), shutdown)
+                .await;
+            if !watched_rx.has_changed().unwrap_or(false) {
+                return;
+            }
+            metrics.controller_restarted();
+        }
+    };
+
+    // Another replica may take over as soon as the lease is lost, so the controllers stop right away.
+    let res = match &leader {
+        Some(leader) => tokio::select! {
+            _ = controllers => Ok(()),
+            _ = leader.hold() => Err(anyhow::anyhow!("lost the leader lease")),
+        },
+        None => {
+            controllers.await;
+            Ok(())
+        }
+    };
+    // The controllers stopped on SIGTERM, so the lease is handed over rather than left to expire.
+    if let Some(leader) = &leader {
+        leader.release().await;
+    }
+    server_handle.stop(true).await;
+    #[cfg(feature = "telemetry")]
+    telemetry::shutdown().await;
+    res
+}
+
+/// The changes planned for the VPAs of an AutoVPA.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoVPAPlan {
+    pub autovpa: String,
+    pub changes: Vec<PlannedChange>,
+    /// Why the changes could not be planned, or only partly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Plans the changes every AutoVPA would make to the VPAs, without making them nor touching the AutoVPAs.
+pub async fn plan(options: &Options) -> anyhow::Result<Vec<AutoVPAPlan>> {
+    let settings = Settings { dry_run: true, read_only: true, ..options.settings() };
+    let (_, config) = watch::channel(Arc::new(options.config()?));
+    let client = options.client().await?;
+    let autovpas = listed_store(settings.apis::<AutoVPA>(&client)).await?;
+    let cluster_autovpas = listed_store(settings.cluster_apis::<ClusterAutoVPA>(&client)).await?;
+    let namespaces = listed_store(settings.cluster_apis::<Namespace>(&client)).await?;
+    let ctx = Arc::new(Ctx {
+        client: client.clone(),
+        targets: TargetIndex::default(),
+        autovpas: autovpas.clone(),
+        cluster_autovpas: cluster_autovpas.clone(),
+        namespaces,
+        recorder: EventRecorder::new(client),
+        backoff: Backoff::default(),
+        metrics: Arc::default(),
+        health: Arc::new(Health::new(DEFAULT_STALL_THRESHOLD)),
+        diagnostics: Arc::default(),
+        permits: None,
+        settings,
+        config,
+    });
+
+    let mut plans = Vec::new();
+    for g in autovpas.state() {
+        plans.push(plan_autovpa(g, &ctx).await);
+    }
+    for g in cluster_autovpas.state() {
+        plans.push(plan_autovpa(g, &ctx).await);
+    }
+    plans.sort_by(|a, b| a.autovpa.cmp(&b.autovpa));
+    Ok(plans)
+}
+
+async fn plan_autovpa<K: AutoVPAKind>(obj: Arc<K>, ctx: &Arc<Ctx>) -> AutoVPAPlan {
+    let autovpa = obj.key().to_string();
+    let res = reconciler(obj, ctx.clone()).await;
+    let reconcile = ctx.diagnostics.last_reconcile(&autovpa).unwrap_or_default();
+    AutoVPAPlan {
+        error: res.err().map(|err| err.to_string()).or(reconcile.last_error),
+        changes: reconcile.plan,
+        autovpa,
+    }
+}
+
+/// A store of the objects of `apis`, listed once.
+async fn listed_store<K>(apis: Vec<Api<K>>) -> Result<Store<K>>
+where
+    K: Resource<DynamicType = ()>
+        + Clone
+        + std::fmt::Debug
+        + DeserializeOwned
+        + Send
+        + Sync
+        + 'static,
+{
+    let (store, mut writer) = reflector::store();
+    let mut objects = Vec::new();
+    for api in apis {
+        objects.extend(api.list(&ListParams::default()).await?.items);
+    }
+    writer.apply_watcher_event(&watcher::Event::Restarted(objects));
+    Ok(store)
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to listen for SIGTERM");
+        terminate.recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate => (),
+    }
+}
+
+/// Keeps `tx` up to date with the registered kinds targeted by some AutoVPA or ClusterAutoVPA,
+/// as the AutoVPAs change or a reload of the config registers other kinds.
+/// The metrics and diagnostics of deleted AutoVPAs are dropped along the way, and `health` learns whether
+/// the controller resyncs some AutoVPAs.
+fn watch_target_kinds(
+    shared: &Shared,
+    tx: watch::Sender<Vec<GroupVersionKind>>,
+) -> impl Future<Output = ()> + Send + 'static {
+    let Shared { autovpas, cluster_autovpas, metrics, health, diagnostics, config, .. } =
+        shared.clone();
+    let autovpa_streams = stream::select(
+        shared.autovpa_events.subscribe().map(|_| ()),
+        shared.cluster_autovpa_events.subscribe().map(|_| ()),
+    );
+    stream::select(autovpa_streams, changes(config.clone())).for_each(move |_| {
+        let (autovpas, cluster_autovpas) = (autovpas.state(), cluster_autovpas.state());
+        let all = autovpas.iter().map(|g| &**g as &dyn AutoVPAResource);
+        let all = all.chain(cluster_autovpas.iter().map(|g| &**g as &dyn AutoVPAResource));
+        let all: Vec<&dyn AutoVPAResource> = all.collect();
+        let keys: HashSet<String> = all.iter().map(|g| g.key().to_string()).collect();
+        metrics.retain_autovpas(|autovpa| keys.contains(autovpa));
+        diagnostics.retain_autovpas(|autovpa| keys.contains(autovpa));
+        let config = config.borrow();
+        // Only valid AutoVPAs are resynced, the other ones wait for a change.
+        let resync = all.iter().any(|g| is_valid(*g, &config)).then(|| config.resync()).flatten();
+        health.resyncing(resync);
+        let gvks = watched_gvks(all, &config.registered);
+        tx.send_if_modified(|watched| {
+            let changed = *watched != gvks;
+            *watched = gvks;
+            changed
+        });
+        futures::future::ready(())
+    })
+}
+
+/// Yields whenever the value of `rx` changes, until its sender is dropped.
+fn changes<T: Send + Sync + 'static>(
+    mut rx: watch::Receiver<T>,
+) -> impl Stream<Item = ()> + Send + Sync + 'static {
+    rx.borrow_and_update();
+    stream::unfold(rx, |mut rx| async move { rx.changed().await.ok().map(|()| ((), rx)) })
+}
+
+async fn run_controller(
+    client: Client,
+    watched: &[GroupVersionKind],
+    shared: Shared,
+    settings: Settings,
+    shutdown: impl Future<Output = ()> + Send + Sync + 'static,
+) {
+    let Shared {
+        targets,
+        autovpas,
+        cluster_autovpas,
+        namespaces,
+        autovpa_events,
+        cluster_autovpa_events,
+        namespace_events,
+        recorder,
+        metrics,
+        health,
+        diagnostics,
+        config,
+    } = shared;
+
+    // The targets and VPAs are watched once for both controllers, until they restart.
+    let mut forwards = Vec::new();
+    let mut target_events = Vec::new();
+    for gvk in watched {
+        for dyn_api in settings.dyn_apis(&client, &ApiResource::from_gvk(gvk)) {
+            let events = counted(watcher(dyn_api, Config::default()), &metrics, &gvk.kind);
+            let (events, forward) = Fanout::new(reported(events, &health));
+            target_events.push((gvk.clone(), events));
+            forwards.push(forward.boxed());
+        }
+    }
+    let mut vpa_events = Vec::new();
+    for vpa_api in settings.apis::<VerticalPodAutoscaler>(&client) {
+        let events =
+            counted(watcher(vpa_api, Config::default()), &metrics, "VerticalPodAutoscaler");
+        let (events, forward) = Fanout::new(reported(events, &health));
+        vpa_events.push(events);
+        forwards.push(forward.boxed());
+    }
+    let watches = Watches {
+        autovpas: autovpa_events,
+        cluster_autovpas: cluster_autovpa_events,
+        vpas: vpa_events,
+        targets: target_events,
+    };
+
+    let autovpa_controller = Controller::for_stream(watches.autovpas.subscribe(), autovpas.clone());
+    let cluster_autovpa_controller = settings.cluster_wide().then(|| {
+        Controller::for_stream(watches.cluster_autovpas.subscribe(), cluster_autovpas.clone())
+    });
+
+    let ctx = Arc::new(Ctx {
+        client,
+        targets,
+        autovpas,
+        cluster_autovpas,
+        namespaces,
+        recorder,
+        backoff: Backoff::default(),
+        metrics,
+        health,
+        diagnostics,
+        permits: (settings.concurrency > 0).then(|| Semaphore::new(settings.concurrency)),
+        settings,
+        config,
+    });
+
+    // Namespace labels are needed to match `namespaceSelector`, and relabelling a namespace re-evaluates the
+    // ClusterAutoVPAs selecting namespaces by label: the ones selecting it now, and the ones with targets in it before.
+    // AutoVPAs only select their own namespace, so they don't need to see namespaces.
+    let ns_mapper = |ctx: Arc<Ctx>| {
+        move |ns: Namespace| {
+            let name = ns.name_any();
+            let targets = ctx.targets.read().unwrap();
+            ctx.cluster_autovpas
+                .state()
+                .iter()
+                .filter(|g| g.spec.namespace_selector.is_some())
+                .filter(|g| {
+                    selects_namespace(&***g, &name, &ctx.namespaces)
+                        || targets
+                            .get(&g.key())
+                            .is_some_and(|keys| keys.iter().any(|k| k.namespace == name))
+                })
+                .map(|g| ObjectRef::from_obj(&**g))
+                .collect::<Vec<_>>()
+        }
+    };
+    let ns_mapper = recording(ctx.clone(), "Namespace", ns_mapper(ctx.clone()));
+    let cluster_autovpa_controller = cluster_autovpa_controller
+        .map(|c| c.watches_stream(namespace_events.subscribe(), ns_mapper));
+
+    let shutdown = shutdown.shared();
+    let cluster_shutdown = shutdown.clone();
+    let controllers = async {
+        futures::join!(run_kind(autovpa_controller, ctx.clone(), &watches, shutdown), async {
+            if let Some(controller) = cluster_autovpa_controller {
+                run_kind(controller, ctx, &watches, cluster_shutdown).await
+            }
+        })
+    };
+    // The watches never end on their own, they stop along with the controllers.
+    let forwards = async {
+        futures::future::join_all(forwards).await;
+        futures::future::pending::<()>().await
+    };
+    tokio::select! {
+        _ = controllers => (),
+        _ = forwards => (),
+    }
+}
+
+/// The objects touched by the watches of a run of the controllers, shared by both of them.
+struct Watches {
+    autovpas: Fanout<AutoVPA>,
+    cluster_autovpas: Fanout<ClusterAutoVPA>,
+    vpas: Vec<Fanout<VerticalPodAutoscaler>>,
+    targets: Vec<(GroupVersionKind, Fanout<DynamicObject>)>,
+}
+
+/// Runs the controller of the AutoVPA kind `K`, sharing `ctx` and `watches` with the controller of the other kind.
+async fn run_kind<K: AutoVPAKind>(
+    mut controller: Controller<K>,
+    ctx: Arc<Ctx>,
+    watches: &Watches,
+    shutdown: impl Future<Output = ()> + Send + Sync + 'static,
+) {
+    for (gvk, events) in &watches.targets {
+        controller = controller.watches_stream_with(
+            events.subscribe(),
+            recording(ctx.clone(), &gvk.kind, target_mapper::<K>(ctx.clone(), gvk.clone())),
+            ApiResource::from_gvk(gvk),
+        );
+    }
+
+    // `owns` would look the owner up in the namespace of the VPA, which misses cluster-scoped owners.
+    for events in &watches.vpas {
+        let owner_mapper = recording(ctx.clone(), "VerticalPodAutoscaler", owner_mapper::<K>);
+        controller = controller.watches_stream(events.subscribe(), owner_mapper);
+    }
+    let autovpa_mapper = recording(ctx.clone(), "AutoVPA", conflict_mapper::<K, AutoVPA>);
+    let cluster_autovpa_mapper =
+        recording(ctx.clone(), "ClusterAutoVPA", conflict_mapper::<K, ClusterAutoVPA>);
+    controller
+        .watches_stream(watches.autovpas.subscribe(), autovpa_mapper)
+        .watches_stream(watches.cluster_autovpas.subscribe(), cluster_autovpa_mapper)
+        // The AutoVPAs listed before a restart don't show up on the shared watches again, so they are all reconciled
+        // once on start. The config may change how every AutoVPA renders, or which targets it may select.
+        .reconcile_all_on(
+            stream::once(futures::future::ready(())).chain(changes(ctx.config.clone())),
+        )
+        .shutdown_on_signal()
+        .graceful_shutdown_on(shutdown)
+        .run(reconciler, error_policy, ctx.clone())
+        .for_each(|res| {
+            // The shared watches report their own failures, reconciles report progress whether they failed or not.
+            if let Ok(_) | Err(controller::Error::ReconcilerFailed(..)) = &res {
+                ctx.health.reconciled();
+            }
+            ctx.health.progressed();
+            async move {
+                match res {
+                    Ok((obj_ref, action)) => {
+                        info!(autovpa = %AutoVPAKey::from_ref(&obj_ref), ?action, "reconciled")
+                    }
+                    Err(controller::Error::ReconcilerFailed(err, obj_ref)) => {
+                        let autovpa = AutoVPAKey {
+                            kind: K::kind(&()).to_string(),
+                            namespace: obj_ref.namespace,
+                            name: obj_ref.name,
+                        };
+                        error!(autovpa = %autovpa, error = %err, "Failed to reconcile object")
+                    }
+                    Err(err) => {
+                        error!(error = %err, "Failed to reconcile object")
+                    }
+                }
+            }
+        })
+        .await;
+}
+
+/// Shares the objects touched by a watch among its subscribers, so both controllers watch each kind once.
+#[derive(Clone)]
+struct Fanout<K> {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<K>>>>,
+}
+
+impl<K: Clone + Send + 'static> Fanout<K> {
+    /// Shares the objects touched by `events` once the returned future runs. The errors are left for the watch to
+    /// retry, [`counted`] and [`reported`] record them.
+    fn new(
+        events: impl Stream<Item = WatchEvent<K>> + Send + 'static,
+    ) -> (Self, impl Future<Output = ()> + Send + 'static) {
+        let fanout = Self { subscribers: Arc::default() };
+        let subscribers = fanout.subscribers.clone();
+        let forward = events
+            .touched_objects()
+            .filter_map(|o| futures::future::ready(o.ok()))
+            .for_each(move |obj| {
+                subscribers.lock().unwrap().retain(|tx| tx.unbounded_send(obj.clone()).is_ok());
+                futures::future::ready(())
+            });
+        (fanout, forward)
+    }
+
+    /// The objects touched from now on, as a watch which never fails.
+    fn subscribe(&self) -> impl Stream<Item = Result<K, watcher::Error>> + Send + 'static {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx.map(Ok)
+    }
+}
+
+/// An event of a watch, or the error it failed with.
+type WatchEvent<K> = Result<watcher::Event<K>, watcher::Error>;
+
+/// Reflects the objects of `apis` into a store, reporting to `health` once they are all listed and whether
+/// their watches fail.
+fn synced_reflector<K>(
+    apis: Vec<Api<K>>,
+    health: &Arc<Health>,
+    metrics: &Arc<Metrics>,
+    name: &'static str,
+) -> (Store<K>, impl Stream<Item = WatchEvent<K>>)
+where
+    K: Resource<DynamicType = ()>
+        + Clone
+        + std::fmt::Debug
+        + DeserializeOwned
+        + Send
+        + Sync
+        + 'static,
+{
+    let count = apis.len();
+    let (store, stream) = merged_reflector(apis, metrics);
+    let health = health.clone();
+    let mut listed = HashSet::new();
+    let stream = stream.map(move |(api, event)| {
+        match &event {
+            Ok(watcher::Event::Restarted(_)) => {
+                listed.insert(api);
+                if listed.len() == count {
+                    health.synced(name);
+                }
+                health.progressed();
+            }
+            Ok(_) => health.progressed(),
+            Err(_) => health.failed(),
+        }
+        event
+    });
+    (store, stream)
+}
+
+/// Reports to `health` whether the watch yielding `events` makes progress or fails.
+fn reported<K>(
+    events: impl Stream<Item = WatchEvent<K>>,
+    health: &Arc<Health>,
+) -> impl Stream<Item = WatchEvent<K>> {
+    let health = health.clone();
+    events.inspect(move |event| match event {
+        Ok(_) => health.progressed(),
+        Err(_) => health.failed(),
+    })
+}
+
+/// Reflects the objects of all of `apis`, like the Apis of several namespaces, into a single store.
+/// Each Api is also reflected into a store of its own, so a relist of one doesn't drop the objects of the others.
+/// The events are tagged with the index of their Api.
+fn merged_reflector<K>(
+    apis: Vec<Api<K>>,
+    metrics: &Arc<Metrics>,
+) -> (Store<K>, impl Stream<Item = (usize, WatchEvent<K>)>)
+where
+    K: Resource<DynamicType = ()>
+        + Clone
+        + std::fmt::Debug
+        + DeserializeOwned
+        + Send
+        + Sync
+        + 'static,
+{
+    let (store, mut writer) = reflector::store();
+    let (stores, streams): (Vec<Store<K>>, Vec<_>) = apis
+        .into_iter()
+        .enumerate()
+        .map(|(i, api)| {
+            let (store, writer) = reflector::store();
+            let stream = counted(watcher(api, Config::default()), metrics, &K::kind(&()));
+            let stream = reflector(writer, stream);
+            (store, stream.map(move |event| (i, event)).boxed())
+        })
+        .unzip();
+    let stream = stream::select_all(streams).inspect(move |(_, event)| match event {
+        Ok(watcher::Event::Restarted(_)) => {
+            let objects = stores.iter().flat_map(Store::state).map(|o| K::clone(&o)).collect();
+            writer.apply_watcher_event(&watcher::Event::Restarted(objects));
+        }
+        Ok(event) => writer.apply_watcher_event(event),
+        Err(_) => (),
+    });
+    (store, stream)
+}
+
+/// Counts the relists of the watch of `kind` yielding `events` past its initial list, and its errors.
+fn counted<K>(
+    events: impl Stream<Item = WatchEvent<K>>,
+    metrics: &Arc<Metrics>,
+    kind: &str,
+) -> impl Stream<Item = WatchEvent<K>> {
+    let (metrics, kind) = (metrics.clone(), kind.to_string());
+    let mut listed = false;
+    events.inspect(move |event| match event {
+        Ok(watcher::Event::Restarted(_)) if std::mem::replace(&mut listed, true) => {
+            metrics.watch_restarted(&kind)
+        }
+        Ok(_) => (),
+        Err(_) => metrics.watch_failed(&kind),
+    })
+}
+
+/// Wraps `mapper` to record the `kind` object it maps as a trigger of the reconciles, for the diagnostics endpoint.
+fn recording<K, T, I>(
+    ctx: Arc<Ctx>,
+    kind: &str,
+    mapper: impl Fn(T) -> I + Send + Sync + 'static,
+) -> impl Fn(T) -> Vec<ObjectRef<K>> + Send + Sync + 'static
+where
+    K: AutoVPAKind,
+    T: Resource,
+    I: IntoIterator<Item = ObjectRef<K>>,
+{
+    let kind = kind.to_string();
+    move |obj: T| {
+        let trigger = match obj.namespace() {
+            Some(namespace) => format!("{} {}/{}", kind, namespace, obj.name_any()),
+            None => format!("{} {}", kind, obj.name_any()),
+        };
+        let autovpas: Vec<ObjectRef<K>> = mapper(obj).into_iter().collect();
+        for autovpa in &autovpas {
+            ctx.diagnostics.triggered(AutoVPAKey::from_ref(autovpa).to_string(), trigger.clone());
+        }
+        autovpas
+    }
+}
+
+/// Maps a workload of `gvk` to the AutoVPAs of kind `K` which generate its VPA, or generated it before.
+fn target_mapper<K: AutoVPAKind>(
+    ctx: Arc<Ctx>,
+    gvk: GroupVersionKind,
+) -> impl Fn(DynamicObject) -> Vec<ObjectRef<K>> + Send + Sync + 'static {
+    move |o: DynamicObject| {
+        let candidates = ctx.candidates();
+        let config = ctx.config();
+        let controlled = is_controlled_by_target(&o, &config.registered);
+        let protected = o.namespace().is_some_and(|ns| config.is_protected(&ns));
+        let candidates = candidates.iter().map(|g| &**g).filter(|_| !controlled && !protected);
+        let winner = select_winner(contenders(candidates, &gvk, &o, &ctx.namespaces, &config));
+        debug!(
+            autovpa = winner.map(|g| g.key().to_string()),
+            target_kind = %gvk.kind,
+            target_namespace = o.namespace(),
+            target_name = %o.name_any(),
+            "target selected"
+        );
+        let mut autovpas: Vec<ObjectRef<K>> =
+            winner.and_then(|g| g.key().object_ref()).into_iter().collect();
+
+        // AutoVPAs which generated a VPA for this workload before need to see it too, in case it stopped matching.
+        let key = TargetKey {
+            kind: gvk.kind.clone(),
+            namespace: o.namespace().unwrap_or_default(),
+            name: o.name_any(),
+        };
+        for (autovpa, keys) in ctx.targets.read().unwrap().iter() {
+            let Some(autovpa) = autovpa.object_ref() else {
+                continue;
+            };
+            if keys.contains(&key) && !autovpas.contains(&autovpa) {
+                autovpas.push(autovpa);
+            }
+        }
+        autovpas
+    }
+}
+
+/// Maps a VPA to the AutoVPA of kind `K` controlling it.
+fn owner_mapper<K: AutoVPAKind>(vpa: VerticalPodAutoscaler) -> Option<ObjectRef<K>> {
+    let owner = vpa
+        .owner_references()
+        .iter()
+        .find(|o| o.controller == Some(true) && o.kind == K::kind(&()))?;
+    let obj_ref = ObjectRef::new(&owner.name);
+    Some(if K::namespaced() { obj_ref.within(&vpa.namespace()?) } else { obj_ref })
+}
+
+/// A change to one AutoVPA may hand a conflicted target over to another one, so its peers of kind `K` re-evaluate.
+fn conflict_mapper<K: AutoVPAKind, P: AutoVPAResource>(g: P) -> Vec<ObjectRef<K>> {
+    let key = g.key().to_string();
+    let peers: HashSet<&String> = g
+        .status()
+        .iter()
+        .flat_map(|s| &s.conflicted)
+        .flat_map(|c| std::iter::once(&c.winner).chain(&c.losers))
+        .filter(|peer| **peer != key)
+        .collect();
+    peers.into_iter().filter_map(|peer| AutoVPAKey::parse(peer)?.object_ref()).collect()
+}
+
+fn stall_threshold() -> Result<Duration> {
+    let Ok(threshold) = std::env::var(STALL_THRESHOLD_ENV) else {
+        return Ok(DEFAULT_STALL_THRESHOLD);
+    };
+    let secs = threshold.trim().parse::<u64>().ok().filter(|s| *s > 0);
+    secs.map(Duration::from_secs).ok_or(Error::InvalidStallThreshold(threshold))
+}
+
+/// Whether `target` is controlled by a workload of a registered kind, like a Job spawned by a CronJob
+/// or a ReplicaSet of a Deployment. Only the top-level controller gets a VPA.
+fn is_controlled_by_target(target: &DynamicObject, registered: &[GroupVersionKind]) -> bool {
+    target.owner_references().iter().filter(|o| o.controller == Some(true)).any(|o| {
+        let group = o.api_version.rsplit_once('/').map_or("", |(group, _)| group);
+        registered.iter().any(|g| g.group == group && g.kind == o.kind)
+    })
+}
+
+/// Whether `autovpa` targets workloads of `kind`.
+fn targets_kind(autovpa: &dyn AutoVPAResource, kind: &str) -> bool {
+    match &autovpa.spec().target_kinds {
+        Some(kinds) => kinds.iter().any(|k| k == kind),
+        None => DEFAULT_TARGET_KINDS.contains(&kind),
+    }
+}
+
+/// The registered kinds targeted by `autovpa`. Requested kinds the controller doesn't know are an error.
+fn target_gvks(
+    autovpa: &dyn AutoVPAResource,
+    registered: &[GroupVersionKind],
+) -> Result<Vec<GroupVersionKind>> {
+    for kind in autovpa.spec().target_kinds.iter().flatten() {
+        if !registered.iter().any(|g| &g.kind == kind) {
+            return Err(Error::UnknownKind(kind.clone()));
+        }
+    }
+    Ok(registered.iter().filter(|g| targets_kind(autovpa, &g.kind)).cloned().collect())
+}
+
+/// The registered kinds targeted by any of `autovpas`, which are the ones to watch.
+fn watched_gvks<'a>(
+    autovpas: impl IntoIterator<Item = &'a dyn AutoVPAResource>,
+    registered: &[GroupVersionKind],
+) -> Vec<GroupVersionKind> {
+    let autovpas: Vec<&dyn AutoVPAResource> = autovpas.into_iter().collect();
+    registered
+        .iter()
+        .filter(|g| autovpas.iter().any(|a| targets_kind(*a, &g.kind)))
+        .cloned()
+        .collect()
+}
+
+#[instrument(name = "reconcile", skip_all, fields(autovpa = %obj.key(), targets = field::Empty, trace_id = field::Empty))]
+async fn reconciler<K: AutoVPAKind>(obj: Arc<K>, ctx: Arc<Ctx>) -> Result<Action, Error> {
+    #[cfg(feature = "telemetry")]
+    if let Some(trace_id) = telemetry::trace_id() {
+        Span::current().record("trace_id", field::display(trace_id));
+    }
+    let _permit = match &ctx.permits {
+        Some(permits) => {
+            let _waiting = ctx.metrics.wait_for_slot();
+            Some(permits.acquire().await.expect("reconcile permits are never closed"))
+        }
+        None => None,
+    };
+    let _measurer = ctx.metrics.measure_reconcile(obj.key().to_string());
+    ctx.diagnostics.reconciling(obj.key().to_string());
+    let client = ctx.client.clone();
+    let config = ctx.config();
+    let generation = obj.meta().generation;
+    let obj_ref = obj.object_ref(&());
+    let mut events = EventBatch::default();
+    // A dry run goes through the API server with `dryRun`, so the writes are still validated.
+    let dry_run = ctx.settings.dry_run || obj.spec().dry_run;
+    let mut plan = Vec::new();
+    let mut conditions = obj.status().map(|s| s.conditions.clone()).unwrap_or_default();
+    let api = K::api(client.clone(), obj.own_namespace());
+
+    // Retrying cannot fix an invalid spec, so it is only reported until the AutoVPA changes.
+    let selection = parse_selection(&*obj, &config.registered)
+        .and_then(|selection| Ok((selection, vpa_template(&*obj, &config)?)));
+    let ((label_selector_query, gvks), template) = match selection {
+        Ok(selection) => selection,
+        Err(err) => {
+            warn!(error = %err, "invalid selection");
+            set_condition(
+                &mut conditions,
+                "InvalidSelector",
+                true,
+                err.reason(),
+                err.to_string(),
+                generation,
+            );
+            set_condition(
+                &mut conditions,
+                "Ready",
+                false,
+                "InvalidSelector",
+                err.to_string(),
+                generation,
+            );
+            let status = invalid_status(&conditions, generation);
+            events.warning(obj_ref.clone(), "InvalidSelector", "Reconcile", err.to_string());
+            if !ctx.settings.read_only {
+                ctx.recorder.publish(&obj_ref, events).await;
+                api.patch_status(&obj.name_any(), &Default::default(), &Patch::Merge(status))
+                    .await?;
+            }
+            ctx.diagnostics.reconciled(obj.key().to_string(), vec![], vec![], vec![]);
+            Span::current().record("targets", 0);
+            ctx.diagnostics.failed(obj.key().to_string(), err.to_string());
+            ctx.backoff.reset(&obj.key());
+            ctx.metrics.set_retries_pending(ctx.backoff.pending());
+            return Ok(Action::await_change());
+        }
+    };
+    if awaits_namespaces(&*obj, &ctx.settings, &ctx.namespaces) {
+        debug!("namespaces not synced yet, requeue");
+        return Ok(Action::requeue(Duration::from_secs(1)));
+    }
+    let oref = obj.controller_owner_ref(&()).ok_or(Error::InvalidOwnerRef())?;
+
+    // An AutoVPA only lists its own namespace, a ClusterAutoVPA the whole cluster.
+    let vpa_list: Api<VerticalPodAutoscaler> = match obj.own_namespace() {
+        Some(namespace) => Api::namespaced(client.clone(), namespace),
+        None => Api::all(client.clone()),
+    };
+    let vpas = vpa_list.list(&ListParams::default()).await?.items;
+
+    let mut excluded = 0;
+    let mut matched_targets = HashSet::new();
+    let mut matched_vpas = HashSet::new();
+    let mut conflicted = Vec::new();
+    let mut results = Vec::new();
+    let mut failures = Vec::new();
+    let mut rendered = Vec::new();
+    // The target each VPA name was generated for, by namespace and name.
+    let mut generated_for: HashMap<(String, String), String> = HashMap::new();
+    for gvk in &gvks {
+        let api_resource = ApiResource::from_gvk(gvk);
+        let dyn_api: Api<DynamicObject> = match obj.own_namespace() {
+            Some(namespace) => Api::namespaced_with(client.clone(), namespace, &api_resource),
+            None => Api::all_with(client.clone(), &api_resource),
+        };
+
+        let targets = dyn_api
+            .list(&ListParams {
+                label_selector: label_selector_query.clone(),
+                ..Default::default()
+            })
+            .await?
+            .items;
+
+        for target in targets {
+            let target_ref = target.object_ref(&api_resource);
+            let target_name = target
+                .meta()
+                .name
+                .clone()
+                .ok_or_else(|| Error::MissingObjectKey(".metadata.name"))?;
+
+            let target_namespace =
+                target.namespace().ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
+
+            // The logs of the target carry its fields through a span, which must not stay entered across awaits.
+            let span = info_span!("target", target_kind = %gvk.kind, target_namespace, target_name);
+            async {
+                if config.is_protected(&target_namespace) {
+                    debug!("skip target in a protected namespace");
+                    return;
+                }
+                if is_controlled_by_target(&target, &config.registered) {
+                    debug!("skip target in favour of its controller");
+                    return;
+                }
+                if !selects_target(&*obj, &target, &ctx.namespaces) {
+                    debug!("skip target not selected");
+                    return;
+                }
+                if is_excluded(&*obj, &target) {
+                    debug!("skip excluded target");
+                    excluded += 1;
+                    return;
+                }
+                // The targets keeping a VPA, or skipped for an existing one, are indexed so their AutoVPA sees
+                // them stop matching.
+                let target_key = TargetKey {
+                    kind: gvk.kind.clone(),
+                    namespace: target_namespace.clone(),
+                    name: target_name.clone(),
+                };
+
+                let vpa_target_ref = VerticalPodAutoscalerTargetRef {
+                    api_version: Some(gvk.api_version()),
+                    kind: gvk.kind.clone(),
+                    name: target_name.clone(),
+                };
+
+                let in_namespace = |vpa: &&VerticalPodAutoscaler| {
+                    vpa.namespace().as_deref() == Some(target_namespace.as_str())
+                };
+                // A VPA adopted earlier keeps its name, other generated ones follow the naming scheme.
+                let adopted = vpas.iter().filter(in_namespace).find(|vpa| {
+                    is_generated(vpa)
+                        && vpa.annotations().contains_key(ADOPTED_ANNOTATION)
+                        && targets_workload(vpa, &gvk.kind, &target_name)
+                });
+                let vpa_name =
+                    generated_name(adopted, &template, &gvk.kind, &target_namespace, &target_name);
+                let mut adopting = adopted.is_some();
+
+                // The store may lag behind, so `obj` itself always takes part with its latest spec.
+                let candidates = ctx.candidates();
+                let others = candidates.iter().map(|g| &**g).filter(|g| g.key() != obj.key());
+                let mut selected: Vec<&dyn AutoVPAResource> =
+                    contenders(others, gvk, &target, &ctx.namespaces, &config).collect();
+                selected.push(&*obj);
+                let winner = select_winner(selected.iter().copied());
+                // The VPA the winner generates for the target.
+                let winner_vpa_name = winner.and_then(|g| {
+                    let template = vpa_template(g, &config).ok()?;
+                    generated_name(adopted, &template, &gvk.kind, &target_namespace, &target_name)
+                        .ok()
+                });
+                let winner = winner.map(|g| g.key());
+                if selected.len() > 1 {
+                    let winner = winner.as_ref().map(ToString::to_string).unwrap_or_default();
+                    let mut losers: Vec<String> = selected
+                        .iter()
+                        .map(|g| g.key().to_string())
+                        .filter(|n| *n != winner)
+                        .collect();
+                    losers.sort();
+                    let note = format!(
+                        "Selected by several AutoVPAs: {} generates its VPA over {}",
+                        winner,
+                        losers.join(", ")
+                    );
+                    events.warning(target_ref.clone(), "Conflict", "Reconcile", note);
+                    conflicted.push(ConflictedTarget {
+                        kind: gvk.kind.clone(),
+                        namespace: target_namespace.clone(),
+                        name: target_name.clone(),
+                        winner,
+                        losers,
+                    });
+                }
+                if winner != Some(obj.key()) {
+                    debug!("skip target selected by a higher priority autovpa");
+                    // A VPA of the same name is taken over by the winner on its next apply, any other one is pruned.
+                    if let (Some(winner_vpa_name), Ok(vpa_name)) = (winner_vpa_name, vpa_name) {
+                        if winner_vpa_name == vpa_name {
+                            matched_targets.insert(target_key);
+                            matched_vpas.insert((target_namespace, vpa_name));
+                        }
+                    }
+                    return;
+                }
+
+                // Applying several targets to the same VPA would leave it to the last one.
+                let vpa_name = vpa_name.and_then(|vpa_name| {
+                    match generated_for.entry((target_namespace.clone(), vpa_name.clone())) {
+                        Entry::Occupied(other) => Err(Error::InvalidNameTemplate(format!(
+                            "vpa {} is also generated for {}",
+                            vpa_name,
+                            other.get()
+                        ))),
+                        Entry::Vacant(entry) => {
+                            entry.insert(format!("{}/{}", gvk.kind, target_name));
+                            Ok(vpa_name)
+                        }
+                    }
+                });
+                let mut vpa_name = match vpa_name {
+                    Ok(vpa_name) => vpa_name,
+                    Err(err) => {
+                        error!(error = %err, "skip target without a vpa name of its own");
+                        events.warning(
+                            target_ref.clone(),
+                            "InvalidName",
+                            "ApplyVPA",
+                            err.to_string(),
+                        );
+                        results.push(TargetResult {
+                            kind: gvk.kind.clone(),
+                            namespace: target_namespace.clone(),
+                            name: target_name.clone(),
+                            vpa_name: String::new(),
+                            result: TargetOutcome::Failed,
+                            error: Some(err.to_string()),
+                        });
+                        return;
+                    }
+                };
+
+                let mut spec = VerticalPodAutoscalerSpec {
+                    target_ref: Some(vpa_target_ref),
+                    ..template.template.clone()
+                };
+                if let Err(err) = overrides::apply_overrides(&mut spec, target.annotations()) {
+                    error!(error = %err, "skip target with invalid overrides");
+                    events.warning(
+                        target_ref.clone(),
+                        "InvalidOverride",
+                        "ApplyVPA",
+                        err.to_string(),
+                    );
+                    results.push(TargetResult {
+                        kind: gvk.kind.clone(),
+                        namespace: target_namespace.clone(),
+                        name: target_name.clone(),
+                        vpa_name: vpa_name.clone(),
+                        result: TargetOutcome::Failed,
+                        error: Some(err.to_string()),
+                    });
+                    // Keep the VPA generated before the annotations went wrong.
+                    matched_targets.insert(target_key);
+                    matched_vpas.insert((target_namespace, vpa_name));
+                    return;
+                }
+
+                let vpa_api: Api<VerticalPodAutoscaler> =
+                    Api::namespaced(client.clone(), &target_namespace);
+
+                let mut existing =
+                    vpas.iter().filter(in_namespace).find(|vpa| vpa.name_any() == vpa_name);
+                let adoption = adoption(
+                    &obj.spec().adoption_policy,
+                    vpas.iter().filter(in_namespace),
+                    &gvk.kind,
+                    &target_name,
+                    &vpa_name,
+                );
+                let mut skip = |reason: String| {
+                    debug!(vpa_name, reason, "skip target with an existing vpa");
+                    results.push(TargetResult {
+                        kind: gvk.kind.clone(),
+                        namespace: target_namespace.clone(),
+                        name: target_name.clone(),
+                        vpa_name: vpa_name.clone(),
+                        result: TargetOutcome::Skipped,
+                        error: Some(reason),
+                    });
+                    matched_targets.insert(target_key.clone());
+                };
+                match adoption {
+                    Adoption::None => (),
+                    Adoption::Skip(reason) => {
+                        skip(reason);
+                        return;
+                    }
+                    Adoption::Ambiguous(reason) => {
+                        let note = format!("Adopt no VPA: {}", reason);
+                        skip(reason);
+                        events.warning(target_ref.clone(), "AmbiguousAdoption", "AdoptVPA", note);
+                        return;
+                    }
+                    Adoption::Adopt(vpa) => {
+                        vpa_name = vpa.name_any();
+                        existing = Some(vpa);
+                        adopting = true;
+                    }
+                    Adoption::Replace(replaced) => {
+                        let params = DeleteParams { dry_run, ..Default::default() };
+                        let mut delete_error = None;
+                        for vpa in replaced {
+                            match vpa_api.delete(&vpa.name_any(), &params).await {
+                                Ok(_) if dry_run => {
+                                    plan.push(PlannedChange {
+                                        action: PlannedAction::Delete,
+                                        namespace: target_namespace.clone(),
+                                        vpa_name: vpa.name_any(),
+                                        target: Some(format!("{}/{}", gvk.kind, target_name)),
+                                        spec: None,
+                                    });
+                                    if vpa.name_any() == vpa_name {
+                                        existing = None;
+                                    }
+                                }
+                                Ok(_) => {
+                                    info!(
+                                        vpa_name = %vpa.name_any(),
+                                        replaced_by = vpa_name,
+                                        "deleted replaced vpa"
+                                    );
+                                    let note = format!(
+                                        "Deleted VPA {}/{} replaced by {}",
+                                        target_namespace,
+                                        vpa.name_any(),
+                                        vpa_name
+                                    );
+                                    events.normal(
+                                        target_ref.clone(),
+                                        "VPADeleted",
+                                        "DeleteVPA",
+                                        note,
+                                    );
+                                    if vpa.name_any() == vpa_name {
+                                        existing = None;
+                                    }
+                                }
+                                Err(err) => {
+                                    error!(
+                                        vpa_name = %vpa.name_any(),
+                                        error = %err,
+                                        "delete replaced vpa failed"
+                                    );
+                                    let note = format!(
+                                        "Delete VPA {}/{} replaced by {} failed: {}",
+                                        target_namespace,
+                                        vpa.name_any(),
+                                        vpa_name,
+                                        err
+                                    );
+                                    events.warning(
+                                        target_ref.clone(),
+                                        "DeleteFailed",
+                                        "DeleteVPA",
+                                        note,
+                                    );
+                                    delete_error = Some(err.to_string());
+                                }
+                            }
+                        }
+                        // Generating the VPA next to the one still there would give the workload two VPAs.
+                        if let Some(err) = delete_error {
+                            failures.push(format!(
+                                "{} {}/{}: {}",
+                                gvk.kind, target_namespace, target_name, err
+                            ));
+                            results.push(TargetResult {
+                                kind: gvk.kind.clone(),
+                                namespace: target_namespace.clone(),
+                                name: target_name.clone(),
+                                vpa_name: vpa_name.clone(),
+                                result: TargetOutcome::Failed,
+                                error: Some(err),
+                            });
+                            matched_targets.insert(target_key.clone());
+                            return;
+                        }
+                    }
+                }
+
+                rendered.push(RenderedVPA {
+                    namespace: target_namespace.clone(),
+                    name: vpa_name.clone(),
+                    target: format!("{}/{}", gvk.kind, target_name),
+                    spec: spec.clone(),
+                });
+                let drifted = existing.is_some_and(|vpa| is_generated(vpa) && is_drifted(vpa));
+                if drifted && obj.spec().drift_policy == DriftPolicy::Report {
+                    let note = format!(
+                        "VPA {}/{} was edited outside of its AutoVPA",
+                        target_namespace, vpa_name
+                    );
+                    warn!(vpa_name, "vpa drifted");
+                    events.warning(target_ref.clone(), "DriftDetected", "ApplyVPA", note.clone());
+                    results.push(TargetResult {
+                        kind: gvk.kind.clone(),
+                        namespace: target_namespace.clone(),
+                        name: target_name.clone(),
+                        vpa_name: vpa_name.clone(),
+                        result: TargetOutcome::Drifted,
+                        error: Some(note),
+                    });
+                    matched_targets.insert(target_key.clone());
+                    matched_vpas.insert((target_namespace, vpa_name));
+                    return;
+                }
+
+                let mut metadata = template.metadata.clone().unwrap_or_default();
+                metadata
+                    .annotations
+                    .get_or_insert_with(Default::default)
+                    .insert(SPEC_HASH_ANNOTATION.to_string(), spec_hash(&spec));
+                if adopting {
+                    metadata
+                        .annotations
+                        .get_or_insert_with(Default::default)
+                        .insert(ADOPTED_ANNOTATION.to_string(), "true".to_string());
+                }
+                let vpa = VerticalPodAutoscaler {
+                    metadata: ObjectMeta {
+                        name: Some(vpa_name.clone()),
+                        namespace: Some(target_namespace.clone()),
+                        owner_references: Some(vec![oref.clone()]),
+                        ..metadata
+                    },
+                    spec,
+                };
+
+                // Adopting and reverting drift take over the fields other managers set on the VPA.
+                let params = PatchParams {
+                    force: adopting || drifted,
+                    dry_run,
+                    ..PatchParams::apply(&ctx.settings.field_manager)
+                };
+                let existing_version = existing.and_then(|vpa| vpa.resource_version());
+                let (result, error) = match vpa_api
+                    .patch(&vpa_name, &params, &Patch::Apply(&vpa))
+                    .await
+                {
+                    Ok(applied) if dry_run => {
+                        if let Some(action) = planned_action(existing, &applied) {
+                            debug!(vpa_name, ?action, "dry run planned vpa change");
+                            plan.push(PlannedChange {
+                                action,
+                                namespace: target_namespace.clone(),
+                                vpa_name: vpa_name.clone(),
+                                target: Some(format!("{}/{}", gvk.kind, target_name)),
+                                spec: Some(applied.spec),
+                            });
+                        }
+                        (TargetOutcome::Planned, None)
+                    }
+                    Ok(applied) => {
+                        info!(vpa_name, "applied vpa");
+                        let vpa = format!("{}/{}", target_namespace, vpa_name);
+                        match existing_version {
+                            None => events.normal(
+                                target_ref.clone(),
+                                "VPACreated",
+                                "ApplyVPA",
+                                format!("Created VPA {}", vpa),
+                            ),
+                            Some(_) if drifted => events.normal(
+                                target_ref.clone(),
+                                "DriftReverted",
+                                "ApplyVPA",
+                                format!("Reverted the edits of VPA {}", vpa),
+                            ),
+                            Some(version)
+                                if applied.resource_version().as_ref() != Some(&version) =>
+                            {
+                                events.normal(
+                                    target_ref.clone(),
+                                    "VPAUpdated",
+                                    "ApplyVPA",
+                                    format!("Updated VPA {}", vpa),
+                                )
+                            }
+                            Some(_) => (),
+                        }
+                        (TargetOutcome::Applied, None)
+                    }
+                    Err(err) => {
+                        error!(vpa_name, error = %err, "apply vpa failed");
+                        let note =
+                            format!("Apply VPA {}/{} failed: {}", target_namespace, vpa_name, err);
+                        events.warning(target_ref.clone(), "ApplyFailed", "ApplyVPA", note);
+                        failures.push(format!(
+                            "{} {}/{}: {}",
+                            gvk.kind, target_namespace, target_name, err
+                        ));
+                        (TargetOutcome::Failed, Some(err.to_string()))
+                    }
+                };
+                results.push(TargetResult {
+                    kind: gvk.kind.clone(),
+                    namespace: target_namespace.clone(),
+                    name: target_name.clone(),
+                    vpa_name: vpa_name.clone(),
+                    result,
+                    error,
+                });
+                matched_targets.insert(target_key);
+                matched_vpas.insert((target_namespace, vpa_name));
+            }
+            .instrument(span)
+            .await;
+        }
+    }
+
+    let pruned = prune_vpas(&ctx, &*obj, vpas, &matched_vpas, dry_run, &mut plan, &mut events);
+    failures.extend(pruned.await?);
+    if !ctx.settings.read_only {
+        ctx.recorder.publish(&obj_ref, events).await;
+    }
+    let mut target_names: Vec<String> =
+        matched_targets.iter().map(|t| format!("{}/{}/{}", t.kind, t.namespace, t.name)).collect();
+    target_names.sort();
+    ctx.targets.write().unwrap().insert(obj.key(), matched_targets);
+
+    conflicted.sort_by(|a: &ConflictedTarget, b| {
+        (&a.kind, &a.namespace, &a.name).cmp(&(&b.kind, &b.namespace, &b.name))
+    });
+    results.sort_by(|a: &TargetResult, b| {
+        (&a.result, &a.kind, &a.namespace, &a.name).cmp(&(
+            &b.result,
+            &b.kind,
+            &b.namespace,
+            &b.name,
+        ))
+    });
+    let count = |outcome| results.iter().filter(|r| r.result == outcome).count() as i32;
+    let (applied, planned, failed, skipped, drifted) = (
+        count(TargetOutcome::Applied),
+        count(TargetOutcome::Planned),
+        count(TargetOutcome::Failed),
+        count(TargetOutcome::Skipped),
+        count(TargetOutcome::Drifted),
+    );
+    let matched = results.len() as i32;
+    Span::current().record("targets", matched);
+    let mut managed_vpas = BTreeMap::new();
+    let managed = |r: &&TargetResult| {
+        !dry_run && matches!(r.result, TargetOutcome::Applied | TargetOutcome::Drifted)
+    };
+    for r in results.iter().filter(managed) {
+        *managed_vpas.entry((r.kind.clone(), r.namespace.clone())).or_default() += 1;
+    }
+    ctx.metrics.set_managed_vpas(obj.key().to_string(), managed_vpas);
+    results.truncate(MAX_TARGET_RESULTS);
+    plan.sort_by(|a: &PlannedChange, b| {
+        (&a.namespace, &a.vpa_name).cmp(&(&b.namespace, &b.vpa_name))
+    });
+    ctx.diagnostics.reconciled(obj.key().to_string(), target_names, rendered, plan.clone());
+    plan.truncate(MAX_TARGET_RESULTS);
+
+    set_condition(&mut conditions, "InvalidSelector", false, "Valid", String::new(), generation);
+    if failed > 0 {
+        let message = format!("{} of {} targets failed", failed, matched);
+        set_condition(
+            &mut conditions,
+            "Degraded",
+            true,
+            "TargetsFailed",
+            message.clone(),
+            generation,
+        );
+        set_condition(&mut conditions, "Ready", false, "TargetsFailed", message, generation);
+    } else {
+        let message = if dry_run {
+            format!("{} of {} targets planned", planned, matched)
+        } else {
+            format!("{} of {} targets applied", applied, matched)
+        };
+        set_condition(&mut conditions, "Degraded", false, "Reconciled", String::new(), generation);
+        set_condition(&mut conditions, "Ready", true, "Reconciled", message, generation);
+    }
+
+    let status = serde_json::json!({
+        "status": AutoVPAStatus {
+            matched,
+            applied,
+            planned,
+            failed,
+            skipped,
+            drifted,
+            excluded,
+            conflicted,
+            targets: results,
+            plan,
+            conditions,
+            observed_generation: generation,
+            last_reconcile_time: Some(Time(Utc::now())),
+        }
+    });
+    if !ctx.settings.read_only {
+        api.patch_status(&obj.name_any(), &Default::default(), &Patch::Merge(status)).await?;
+    }
+
+    // Only failed applies and prunes are retried, other failures wait for the AutoVPA or the workload to change.
+    if !failures.is_empty() {
+        return Err(Error::TargetsFailed(failures));
+    }
+    ctx.backoff.reset(&obj.key());
+    ctx.metrics.set_retries_pending(ctx.backoff.pending());
+    Ok(config.resync().map_or_else(Action::await_change, Action::requeue))
 }
 
-pub async fn run() -> anyhow::Result<()> {
-    init_logging();
+/// The label selector query and the kinds to list the targets of `autovpa` with, once its selection is validated.
+fn parse_selection(
+    autovpa: &dyn AutoVPAResource,
+    registered: &[GroupVersionKind],
+) -> Result<(Option<String>, Vec<GroupVersionKind>)> {
+    let spec = autovpa.spec();
+    if autovpa.own_namespace().is_some() {
+        let namespace_fields = [
+            ("namespaceNames", spec.namespace_names.is_some()),
+            ("namespaceSelector", spec.namespace_selector.is_some()),
+            ("namespacePatterns", spec.namespace_patterns.is_some()),
+        ];
+        if let Some((field, _)) = namespace_fields.iter().find(|(_, set)| *set) {
+            return Err(Error::NamespacedSelection(field));
+        }
+    }
+    let label_selector_query = if let Some(selector) = &spec.object_selector {
+        Some(convert_label_selector_to_query_string(selector)?)
+    } else {
+        None
+    };
+    for patterns in [&spec.namespace_patterns, &spec.name_patterns].into_iter().flatten() {
+        patterns.validate()?;
+    }
+    Ok((label_selector_query, target_gvks(autovpa, registered)?))
+}
 
-    let gvks = vec![
-        GroupVersionKind::gvk("apps", "v1", "Deployment"),
-        GroupVersionKind::gvk("apps", "v1", "StatefulSet"),
-        GroupVersionKind::gvk("apps", "v1", "DaemonSet"),
-        GroupVersionKind::gvk("batch", "v1", "Job"),
-    ];
+/// What to do about the VPAs not generated by an AutoVPA that already exist for a target.
+enum Adoption<'a> {
+    /// There are none, the VPA is generated as usual.
+    None,
+    /// No VPA is generated for the target, for the given reason.
+    Skip(String),
+    /// The VPA is taken over.
+    Adopt(&'a VerticalPodAutoscaler),
+    /// Several VPAs target the workload, so none is taken over nor generated, for the given reason.
+    Ambiguous(String),
+    /// The VPAs are deleted before the VPA is generated.
+    Replace(Vec<&'a VerticalPodAutoscaler>),
+}
 
-    let client = Client::try_default().await.expect("failed to create kube client");
+/// Applies `policy` to the VPAs of the namespace of the target `kind`/`name` not generated by an AutoVPA:
+/// the ones targeting the workload, and the one already named `vpa_name`.
+fn adoption<'a>(
+    policy: &AdoptionPolicy,
+    vpas: impl IntoIterator<Item = &'a VerticalPodAutoscaler>,
+    kind: &str,
+    name: &str,
+    vpa_name: &str,
+) -> Adoption<'a> {
+    let (for_target, name_taken): (Vec<_>, Vec<_>) = vpas
+        .into_iter()
+        .filter(|vpa| {
+            !is_generated(vpa) && (vpa.name_any() == vpa_name || targets_workload(vpa, kind, name))
+        })
+        .partition(|vpa| targets_workload(vpa, kind, name));
+    match policy {
+        _ if for_target.is_empty() && name_taken.is_empty() => Adoption::None,
+        AdoptionPolicy::Skip => {
+            let names: Vec<String> =
+                for_target.iter().chain(&name_taken).map(|vpa| vpa.name_any()).collect();
+            Adoption::Skip(format!("vpa {} already exists", names.join(", ")))
+        }
+        AdoptionPolicy::Adopt => match for_target[..] {
+            [vpa] => Adoption::Adopt(vpa),
+            [] => Adoption::Skip(format!("vpa {} already exists for another target", vpa_name)),
+            _ => {
+                let names: Vec<String> = for_target.iter().map(|vpa| vpa.name_any()).collect();
+                Adoption::Ambiguous(format!("vpas {} all target the workload", names.join(", ")))
+            }
+        },
+        AdoptionPolicy::Replace if !name_taken.is_empty() => {
+            Adoption::Skip(format!("vpa {} already exists for another target", vpa_name))
+        }
+        AdoptionPolicy::Replace => Adoption::Replace(for_target),
+    }
+}
 
-    let gen_api: Api<AutoVPA> = Api::all(client.clone());
-    let vpa_api: Api<VerticalPodAutoscaler> = Api::all(client.clone());
+/// Whether `autovpa` has a valid selection and a template, so it can generate the VPAs of the targets it wins.
+fn is_valid(autovpa: &dyn AutoVPAResource, config: &config::Config) -> bool {
+    parse_selection(autovpa, &config.registered).is_ok() && vpa_template(autovpa, config).is_ok()
+}
 
-    // In sceniro of oam controlled contrllers, there is a oam.dev.namespace label in the generated deployment | statefulset...
-    if let Err(e) = gen_api.list(&ListParams::default().limit(1)).await {
-        error!(
-            "autovpa crd is not querable; {:?}, is the crd intalled?",
-            &e as &dyn std::error::Error
-        );
-        info!("Installation: cargo run --bin crdgen | kubectl apply -f");
-        std::process::exit(1);
+/// The name of the VPA generated for the target `kind` `namespace`/`name`: the one of the VPA adopted for it earlier,
+/// or the rendered name template.
+fn generated_name(
+    adopted: Option<&VerticalPodAutoscaler>,
+    template: &VerticalPodAutoscalerTemplateSpec,
+    kind: &str,
+    namespace: &str,
+    name: &str,
+) -> Result<String> {
+    match adopted {
+        Some(vpa) => Ok(vpa.name_any()),
+        None => utils::render_name(
+            template.name_template.as_deref().unwrap_or(DEFAULT_NAME_TEMPLATE),
+            kind,
+            namespace,
+            name,
+        ),
     }
+}
 
-    if let Err(e) = vpa_api.list(&ListParams::default().limit(1)).await {
-        error!("vpa crd is not querable; {:?}, is the crd intalled?", &e as &dyn std::error::Error);
-        std::process::exit(1);
-    }
+/// The template of `autovpa`, or the default one of the controller config.
+fn vpa_template(
+    autovpa: &dyn AutoVPAResource,
+    config: &config::Config,
+) -> Result<VerticalPodAutoscalerTemplateSpec> {
+    let template = autovpa.spec().vpa_template.as_ref().or(config.default_template.as_ref());
+    let template = template.cloned().ok_or(Error::MissingTemplate)?;
+    template.validate()?;
+    Ok(template)
+}
 
-    let mut controller = Controller::new(gen_api.clone(), Config::default());
-    let store = controller.store();
+/// Sets the `type_` condition, keeping its `lastTransitionTime` unless its status changes.
+fn set_condition(
+    conditions: &mut Vec<Condition>,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: String,
+    observed_generation: Option<i64>,
+) {
+    let status = if status { "True" } else { "False" }.to_string();
+    let last_transition_time = conditions
+        .iter()
+        .find(|c| c.type_ == type_ && c.status == status)
+        .map_or_else(|| Time(Utc::now()), |c| c.last_transition_time.clone());
+    conditions.retain(|c| c.type_ != type_);
+    conditions.push(Condition {
+        type_: type_.to_string(),
+        status,
+        reason: reason.to_string(),
+        message,
+        observed_generation,
+        last_transition_time,
+    });
+    conditions.sort_by(|a, b| a.type_.cmp(&b.type_));
+}
 
-    for gvk in &gvks {
-        let api_resource = ApiResource::from_gvk(gvk);
-        let dyn_api: Api<DynamicObject> = Api::all_with(client.clone(), &api_resource);
-        let dyn_mapper = |store: Store<AutoVPA>| {
-            move |o: DynamicObject| {
-                store
-                    .find(|g| {
-                        let match_namespace =
-                            g.spec.namespace_selector.as_ref().map_or(true, |mn| {
-                                o.namespace().map_or(false, |os| mn.contains(&os))
-                            });
-                        if !match_namespace {
-                            return false;
-                        }
-                        // select "Nothing" when selector is none, select "Everything" when selector is empty struct.
-                        // ref: https://github.com/kubernetes/kubernetes/blob/master/vendor/k8s.io/apimachinery/pkg/apis/meta/v1/helpers.go#L36
-                        let match_labels = g.spec.object_selector.as_ref().map_or(false, |ml| {
-                            o.metadata.labels.as_ref().map_or(true, |ls| utils::match_label(ml, ls))
-                        });
-                        debug!(
-                            "g selector {:?} match {:?} return {:?}",
-                            g.spec.object_selector, o.metadata.name, match_labels
-                        );
-                        match_labels
-                    })
-                    .map(|g| ObjectRef::from_obj(&*g))
-            }
-        };
+/// Whether `autovpa` generates a VPA for `target`. Shared by the watch mappers and the reconciler, so both agree on it.
+fn matches_target(
+    autovpa: &dyn AutoVPAResource,
+    gvk: &GroupVersionKind,
+    target: &DynamicObject,
+    namespaces: &Store<Namespace>,
+) -> bool {
+    targets_kind(autovpa, &gvk.kind)
+        && selects_target(autovpa, target, namespaces)
+        && !is_excluded(autovpa, target)
+}
 
-        controller = controller.watches_with(
-            dyn_api,
-            api_resource,
-            Config::default(),
-            dyn_mapper(store.clone()),
-        );
+/// Whether `target` is selected by the namespace and object selectors of `autovpa`.
+/// An AutoVPA only ever selects targets of its own namespace.
+fn selects_target(
+    autovpa: &dyn AutoVPAResource,
+    target: &DynamicObject,
+    namespaces: &Store<Namespace>,
+) -> bool {
+    let Some(namespace) = target.namespace() else {
+        return false;
+    };
+    if autovpa.own_namespace().is_some_and(|own| own != namespace) {
+        return false;
+    }
+    if !selects_namespace(autovpa, &namespace, namespaces) {
+        return false;
     }
+    if !autovpa.spec().name_patterns.as_ref().is_none_or(|p| p.matches(&target.name_any())) {
+        return false;
+    }
+    // select "Nothing" when selector is none, select "Everything" when selector is empty struct.
+    // ref: https://github.com/kubernetes/kubernetes/blob/master/vendor/k8s.io/apimachinery/pkg/apis/meta/v1/helpers.go#L36
+    autovpa
+        .spec()
+        .object_selector
+        .as_ref()
+        .is_some_and(|ml| utils::match_label(ml, target.labels()))
+}
 
-    controller
-        .owns(vpa_api.clone(), Config::default())
-        .shutdown_on_signal()
-        .run(reconciler, error_policy, Arc::new(Ctx { client: client.clone(), gvks }))
-        .for_each(|res| async move {
-            match res {
-                Ok(o) => info!("reconciled: {:?}", o),
-                Err(err) => {
-                    error!(error = &err as &dyn std::error::Error, "Failed to reconcile object")
-                }
-            }
+/// The status patch of an AutoVPA with an invalid selection, which keeps the counts of its last valid reconcile.
+fn invalid_status(conditions: &[Condition], generation: Option<i64>) -> serde_json::Value {
+    serde_json::json!({
+        "status": {
+            "conditions": conditions,
+            "observedGeneration": generation,
+            "lastReconcileTime": Time(Utc::now()),
+        }
+    })
+}
+
+/// Whether `autovpa` has to wait for the namespaces to be listed to match its `namespaceSelector`.
+/// Every cluster has namespaces, so an empty store has not synced yet and would select nothing.
+/// Namespaces are only watched cluster-wide, otherwise the store stays empty.
+fn awaits_namespaces(
+    autovpa: &dyn AutoVPAResource,
+    settings: &Settings,
+    namespaces: &Store<Namespace>,
+) -> bool {
+    settings.cluster_wide() && autovpa.spec().namespace_selector.is_some() && namespaces.is_empty()
+}
+
+/// Whether `namespace` is selected by both `namespaceNames` and `namespaceSelector` of `autovpa`.
+fn selects_namespace(
+    autovpa: &dyn AutoVPAResource,
+    namespace: &str,
+    namespaces: &Store<Namespace>,
+) -> bool {
+    let spec = autovpa.spec();
+    let match_names =
+        spec.namespace_names.as_ref().is_none_or(|mn| mn.iter().any(|n| n == namespace));
+    match_names
+        && spec.namespace_patterns.as_ref().is_none_or(|p| p.matches(namespace))
+        && spec.namespace_selector.as_ref().is_none_or(|selector| {
+            namespaces
+                .get(&ObjectRef::new(namespace))
+                .is_some_and(|ns| utils::match_label(selector, ns.labels()))
         })
-        .await;
-    Ok(())
 }
 
-async fn reconciler(obj: Arc<AutoVPA>, ctx: Arc<Ctx>) -> Result<Action, Error> {
-    let client = ctx.client.clone();
-    let label_selector_query = if let Some(selector) = &obj.spec.object_selector {
-        Some(convert_label_selector_to_query_string(selector)?)
-    } else {
-        None
-    };
+/// Whether `target` is carved out of `autovpa` by its `excludeSelector` or by the ignore annotation of the target.
+fn is_excluded(autovpa: &dyn AutoVPAResource, target: &DynamicObject) -> bool {
+    target.annotations().get(overrides::IGNORE_ANNOTATION).is_some_and(|v| v == "true")
+        || autovpa
+            .spec()
+            .exclude_selector
+            .as_ref()
+            .is_some_and(|es| utils::match_label(es, target.labels()))
+}
 
-    let oref = obj.controller_owner_ref(&()).ok_or(Error::InvalidOwnerRef())?;
+/// Whether `vpa` is controlled by an AutoVPA or a ClusterAutoVPA, as opposed to maintained by hand.
+fn is_generated(vpa: &VerticalPodAutoscaler) -> bool {
+    vpa.owner_references().iter().any(|o| {
+        o.controller == Some(true)
+            && (o.kind == AutoVPA::kind(&()) || o.kind == ClusterAutoVPA::kind(&()))
+    })
+}
 
-    let mut matched = 0;
-    for gvk in &ctx.gvks {
-        let api_resource = ApiResource::from_gvk(gvk);
-        let dyn_api: Api<DynamicObject> = Api::all_with(client.clone(), &api_resource);
+/// Whether the `targetRef` of `vpa` points at the workload `kind`/`name` in the same namespace.
+fn targets_workload(vpa: &VerticalPodAutoscaler, kind: &str, name: &str) -> bool {
+    vpa.spec.target_ref.as_ref().is_some_and(|r| r.kind == kind && r.name == name)
+}
 
-        let targets = dyn_api
-            .list(&ListParams {
-                label_selector: label_selector_query.clone(),
-                ..Default::default()
-            })
-            .await?
-            .items;
+/// Picks the AutoVPA that generates the VPA for a target selected by several of them:
+/// the highest `priority` wins, ties go to the oldest AutoVPA and then to the smallest key.
+/// The AutoVPAs among `candidates` competing for `target`: the valid ones matching it.
+/// An invalid AutoVPA would win without generating anything, so it doesn't take part.
+fn contenders<'a>(
+    candidates: impl IntoIterator<Item = &'a dyn AutoVPAResource> + 'a,
+    gvk: &'a GroupVersionKind,
+    target: &'a DynamicObject,
+    namespaces: &'a Store<Namespace>,
+    config: &'a config::Config,
+) -> impl Iterator<Item = &'a dyn AutoVPAResource> + 'a {
+    candidates
+        .into_iter()
+        .filter(move |g| matches_target(*g, gvk, target, namespaces) && is_valid(*g, config))
+}
 
-        for target in targets {
-            let target_name = target
-                .meta()
-                .name
-                .clone()
-                .ok_or_else(|| Error::MissingObjectKey(".metadata.name"))?;
+fn select_winner<'a>(
+    candidates: impl IntoIterator<Item = &'a dyn AutoVPAResource>,
+) -> Option<&'a dyn AutoVPAResource> {
+    candidates.into_iter().min_by(|a, b| {
+        b.spec()
+            .priority
+            .cmp(&a.spec().priority)
+            .then_with(|| {
+                a.object_meta().creation_timestamp.cmp(&b.object_meta().creation_timestamp)
+            })
+            .then_with(|| a.key().cmp(&b.key()))
+    })
+}
 
-            let target_namespace =
-                target.namespace().ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
+/// The change a dry-run apply returning `applied` plans for the VPA, `existing` before it.
+fn planned_action(
+    existing: Option<&VerticalPodAutoscaler>,
+    applied: &VerticalPodAutoscaler,
+) -> Option<PlannedAction> {
+    match existing {
+        None => Some(PlannedAction::Create),
+        Some(vpa) if is_changed(vpa, applied) => Some(PlannedAction::Update),
+        Some(_) => None,
+    }
+}
 
-            match &obj.spec.namespace_selector {
-                Some(ns) => {
-                    if !ns.contains(&target_namespace.clone()) {
-                        debug!("skip obj with namespace: {}", target_namespace.clone());
-                        continue;
-                    }
-                }
-                _ => (),
-            }
+/// Whether applying `vpa` changed `applied`, as returned by a dry-run apply, from `vpa`.
+fn is_changed(vpa: &VerticalPodAutoscaler, applied: &VerticalPodAutoscaler) -> bool {
+    vpa.spec != applied.spec
+        || vpa.labels() != applied.labels()
+        || vpa.annotations() != applied.annotations()
+        || vpa.owner_references() != applied.owner_references()
+}
 
-            let target_ref = VerticalPodAutoscalerTargetRef {
-                api_version: Some(gvk.api_version()),
-                kind: gvk.kind.clone(),
-                name: target_name.clone(),
-            };
+fn spec_hash(spec: &VerticalPodAutoscalerSpec) -> String {
+    utils::hex_digest(&serde_json::to_vec(spec).unwrap_or_default())
+}
 
-            let vpa_name = format!("{}-vpa", target_name.clone());
-
-            let vpa = VerticalPodAutoscaler {
-                metadata: ObjectMeta {
-                    name: Some(vpa_name.clone()),
-                    namespace: Some(target_namespace.clone()),
-                    owner_references: Some(vec![oref.clone()]),
-                    ..obj.spec.vpa_template.metadata.clone().unwrap_or(Default::default())
-                },
-                spec: VerticalPodAutoscalerSpec {
-                    target_ref: Some(target_ref),
-                    ..obj.spec.vpa_template.template.clone()
-                },
-            };
+/// Whether the spec of `vpa` was edited since it was last applied. VPAs applied before the hash was recorded never are.
+fn is_drifted(vpa: &VerticalPodAutoscaler) -> bool {
+    vpa.annotations().get(SPEC_HASH_ANNOTATION).is_some_and(|hash| *hash != spec_hash(&vpa.spec))
+}
 
-            let vpa_api: Api<VerticalPodAutoscaler> =
-                Api::namespaced(client.clone(), &target_namespace);
+/// Handles the VPAs owned by `obj` that were not generated in this reconcile, according to its `prunePolicy`.
+/// Returns the VPAs that could not be pruned.
+async fn prune_vpas<K: AutoVPAKind>(
+    ctx: &Ctx,
+    obj: &K,
+    vpas: Vec<VerticalPodAutoscaler>,
+    matched_vpas: &HashSet<(String, String)>,
+    dry_run: bool,
+    plan: &mut Vec<PlannedChange>,
+    events: &mut EventBatch,
+) -> Result<Vec<String>> {
+    let mut failures = vec![];
+    let obj_ref = obj.object_ref(&());
+    let uid = obj.uid().ok_or(Error::MissingObjectKey(".metadata.uid"))?;
+    for mut vpa in stale_vpas(vpas, &uid, matched_vpas) {
+        let Some(action) = prune_action(&obj.spec().prune_policy, &vpa) else {
+            continue;
+        };
+        let vpa_name = vpa.name_any();
+        let vpa_namespace =
+            vpa.namespace().ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
+        let vpa_api: Api<VerticalPodAutoscaler> =
+            Api::namespaced(ctx.client.clone(), &vpa_namespace);
 
-            match vpa_api
-                .patch(&vpa_name, &PatchParams::apply("autovpa.dev"), &Patch::Apply(&vpa))
-                .await
-            {
-                Ok(_) => info!("apply vpa {} successfully", vpa_name),
-                Err(err) => error!("apply vpa failed: {}", err),
-            };
-            matched += 1;
+        // SetOff and Orphan re-apply the live object so the VPA keeps all its fields under our field manager.
+        vpa.metadata.managed_fields = None;
+        vpa.metadata.resource_version = None;
+        let apply = PatchParams { dry_run, ..PatchParams::apply(&ctx.settings.field_manager) };
+        let res = match obj.spec().prune_policy {
+            PrunePolicy::Delete => {
+                let params = DeleteParams { dry_run, ..Default::default() };
+                vpa_api.delete(&vpa_name, &params).await.map(|_| ())
+            }
+            PrunePolicy::SetOff => {
+                vpa.spec.update_policy = Some(VerticalPodAutoscalerUpdatePolicy {
+                    update_mode: Some(VerticalPodAutoscalerUpdateMode::Off),
+                    ..vpa.spec.update_policy.unwrap_or_default()
+                });
+                let hash = spec_hash(&vpa.spec);
+                vpa.annotations_mut().insert(SPEC_HASH_ANNOTATION.to_string(), hash);
+                vpa_api.patch(&vpa_name, &apply, &Patch::Apply(&vpa)).await.map(|_| ())
+            }
+            PrunePolicy::Orphan => {
+                vpa.metadata.owner_references =
+                    Some(vpa.owner_references().iter().filter(|o| o.uid != uid).cloned().collect());
+                vpa_api.patch(&vpa_name, &apply, &Patch::Apply(&vpa)).await.map(|_| ())
+            }
+        };
+        match res {
+            Ok(_) if dry_run => plan.push(PlannedChange {
+                action,
+                namespace: vpa_namespace,
+                vpa_name,
+                target: None,
+                spec: None,
+            }),
+            Ok(_) => {
+                info!(vpa_namespace, vpa_name, policy = ?obj.spec().prune_policy, "pruned vpa");
+                let note = format!(
+                    "Pruned VPA {}/{} with policy {:?}",
+                    vpa_namespace,
+                    vpa_name,
+                    obj.spec().prune_policy
+                );
+                events.normal(obj_ref.clone(), "VPAPruned", "PruneVPA", note);
+            }
+            Err(err) => {
+                error!(vpa_namespace, vpa_name, error = %err, "prune vpa failed");
+                let note = format!("Prune VPA {}/{} failed: {}", vpa_namespace, vpa_name, err);
+                events.warning(obj_ref.clone(), "PruneFailed", "PruneVPA", note);
+                failures.push(format!("prune vpa {}/{}: {}", vpa_namespace, vpa_name, err));
+            }
         }
     }
+    Ok(failures)
+}
 
-    let api: Api<AutoVPA> = Api::all(client.clone());
-
-    let status = serde_json::json!({"status": AutoVPAStatus { matched }});
-    api.patch_status(&obj.name_any(), &Default::default(), &Patch::Merge(status)).await?;
+/// The VPAs controlled by the AutoVPA `uid` that were not generated in this reconcile.
+fn stale_vpas(
+    vpas: Vec<VerticalPodAutoscaler>,
+    uid: &str,
+    matched_vpas: &HashSet<(String, String)>,
+) -> Vec<VerticalPodAutoscaler> {
+    vpas.into_iter()
+        .filter(|vpa| {
+            vpa.owner_references().iter().any(|o| o.controller == Some(true) && o.uid == uid)
+                && !matched_vpas.contains(&(vpa.namespace().unwrap_or_default(), vpa.name_any()))
+        })
+        .collect()
+}
 
-    Ok(Action::await_change())
+/// What `policy` does to the stale `vpa`, nothing if it is already set off.
+fn prune_action(policy: &PrunePolicy, vpa: &VerticalPodAutoscaler) -> Option<PlannedAction> {
+    match policy {
+        PrunePolicy::Delete => Some(PlannedAction::Delete),
+        PrunePolicy::SetOff
+            if vpa.spec.update_policy.as_ref().and_then(|p| p.update_mode.as_ref())
+                == Some(&VerticalPodAutoscalerUpdateMode::Off) =>
+        {
+            None
+        }
+        PrunePolicy::SetOff => Some(PlannedAction::SetOff),
+        PrunePolicy::Orphan => Some(PlannedAction::Orphan),
+    }
 }
 
-fn error_policy(_obj: Arc<AutoVPA>, _error: &Error, _ctx: Arc<Ctx>) -> Action {
-    Action::requeue(Duration::from_secs(5))
+fn error_policy<K: AutoVPAKind>(obj: Arc<K>, error: &Error, ctx: Arc<Ctx>) -> Action {
+    let rate_limits = &ctx.config().rate_limits;
+    let delay =
+        ctx.backoff.next_delay(&obj.key(), rate_limits.base_delay(), rate_limits.max_delay());
+    ctx.metrics.reconcile_failed(obj.key().to_string());
+    ctx.diagnostics.failed(obj.key().to_string(), error.to_string());
+    ctx.metrics.set_retries_pending(ctx.backoff.pending());
+    warn!(autovpa = %obj.key(), ?delay, error = %error, "reconcile failed, retry");
+    Action::requeue(delay)
 }
 
 #[cfg(test)]
 mod test {
-    use std::{collections::BTreeMap, sync::Arc};
+    use std::{collections::HashSet, sync::Arc};
 
-    use futures::TryStreamExt;
-    use k8s_openapi::{
-        api::apps::v1::Deployment,
-        apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::LabelSelector},
-    };
+    use futures::{stream, StreamExt};
+    use k8s_openapi::api::apps::v1::Deployment;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
     use kube::{
-        api::{Patch, PatchParams, DeleteParams},
-        core::GroupVersionKind,
-        runtime::{watcher, WatchStreamExt},
+        api::{DeleteParams, Patch, PatchParams},
+        core::{DynamicObject, GroupVersionKind},
+        runtime::watcher,
         Api, ResourceExt,
     };
 
     use crate::{
-        controller::{reconciler, Ctx},
-        vpa::{
-            ContainerControlledValues::RequestsAndLimits, ContainerPolicies, VerticalPodAutoscaler,
-            VerticalPodAutoscalerResourcePolicy, VerticalPodAutoscalerSpec,
+        config::Config,
+        controller::{
+            adoption, awaits_namespaces, contenders, counted, generated_name, invalid_status,
+            is_controlled_by_target, is_drifted, is_excluded, is_valid, matches_target,
+            parse_selection, planned_action, prune_action, reconciler, select_winner,
+            selects_target, set_condition, spec_hash, stale_vpas, target_gvks, vpa_template,
+            watched_gvks, Adoption, AdoptionPolicy, AutoVPAResource, Ctx, Error, Fanout, LogFormat,
+            NamePatterns, Options, PlannedAction, PrunePolicy, Settings, SPEC_HASH_ANNOTATION,
         },
-        AutoVPA,
+        events::EventRecorder,
+        health::Health,
+        metrics::Metrics,
+        vpa::{VerticalPodAutoscaler, VerticalPodAutoscalerUpdateMode},
+        AutoVPA, ClusterAutoVPA,
     };
 
     #[tokio::test]
     #[ignore = "use k8s current-context"]
     async fn integration_test_apply_vpa() -> anyhow::Result<()> {
         let client = kube::Client::try_default().await.unwrap();
-        let gen_api: Api<AutoVPA> = Api::all(client.clone());
+        let gen_api: Api<ClusterAutoVPA> = Api::all(client.clone());
 
         let autovpa_name = "office-test-autovpa";
 
@@ -318,13 +2735,23 @@ mod test {
             .await
             .unwrap();
 
-
         let auto_vpa = gen_api.get(autovpa_name).await?;
         reconciler(
             Arc::new(auto_vpa.clone()),
             Arc::new(Ctx {
                 client: client.clone(),
-                gvks: vec![GroupVersionKind::gvk("apps", "v1", "Deployment")],
+                targets: Default::default(),
+                autovpas: kube::runtime::reflector::store().0,
+                cluster_autovpas: kube::runtime::reflector::store().0,
+                namespaces: kube::runtime::reflector::store().0,
+                recorder: EventRecorder::new(client.clone()),
+                backoff: Default::default(),
+                metrics: Default::default(),
+                health: Arc::new(Health::new(std::time::Duration::from_secs(300))),
+                diagnostics: Default::default(),
+                permits: None,
+                settings: Settings { field_manager: "autovpa.dev".into(), ..Default::default() },
+                config: tokio::sync::watch::channel(Default::default()).1,
             }),
         )
         .await
@@ -348,19 +2775,564 @@ mod test {
         let vpa = get_expected_vpa();
         dbg!("expected vpa:", vpa);
 
-        workload_api.delete("nginx-deployment", &DeleteParams {..Default::default()}).await?;
+        workload_api.delete("nginx-deployment", &DeleteParams { ..Default::default() }).await?;
         Ok(())
     }
 
-    fn get_test_vpa_gen(name: &str) -> AutoVPA {
-        let test_yaml = format!(
+    #[test]
+    fn test_select_winner() {
+        let mut low = get_test_vpa_gen("low");
+        low.spec.priority = -1;
+        let b = get_test_vpa_gen("b");
+        let a = get_test_vpa_gen("a");
+        let winner =
+            |candidates: [&dyn AutoVPAResource; 3]| select_winner(candidates).map(|g| g.key());
+        assert_eq!(winner([&low, &b, &a]).map(|k| k.name), Some("a".to_string()));
+
+        let mut high = get_test_vpa_gen("z");
+        high.spec.priority = 10;
+        assert_eq!(winner([&a, &high, &b]).map(|k| k.name), Some("z".to_string()));
+        assert!(select_winner(Vec::<&dyn AutoVPAResource>::new()).is_none());
+
+        // An invalid AutoVPA would win without generating anything, so it doesn't take part.
+        let config = Config::default();
+        high.spec.target_kinds = Some(vec!["CloneSet".to_string()]);
+        assert!(!is_valid(&high, &config));
+        let mut templateless = get_test_vpa_gen("templateless");
+        templateless.spec.vpa_template = None;
+        assert!(!is_valid(&templateless, &config));
+        assert!(is_valid(&a, &config));
+
+        // The valid AutoVPA wins the target over the invalid ones of higher priority.
+        high.spec.target_kinds = Some(vec!["Deployment".to_string(), "CloneSet".to_string()]);
+        templateless.spec.priority = 20;
+        let target: DynamicObject = serde_yaml::from_str(
+            r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: santa
+  namespace: ali-office-test
+  labels:
+    app: santa
+        "#,
+        )
+        .unwrap();
+        let gvk = GroupVersionKind::gvk("apps", "v1", "Deployment");
+        let namespaces = kube::runtime::reflector::store().0;
+        let candidates: [&dyn AutoVPAResource; 3] = [&high, &templateless, &low];
+        assert!(candidates.iter().all(|g| matches_target(*g, &gvk, &target, &namespaces)));
+        let winner =
+            select_winner(contenders(candidates, &gvk, &target, &namespaces, &config)).unwrap();
+        assert_eq!(winner.key().name, "low");
+    }
+
+    #[test]
+    fn test_adoption() {
+        let vpa = |name: &str, target: &str, owners: &str| -> VerticalPodAutoscaler {
+            serde_yaml::from_str(&format!(
+                r#"
+apiVersion: autoscaling.k8s.io/v1
+kind: VerticalPodAutoscaler
+metadata:
+  name: {}
+  namespace: default
+  ownerReferences: {}
+spec:
+  targetRef:
+    apiVersion: apps/v1
+    kind: Deployment
+    name: {}
+        "#,
+                name, owners, target
+            ))
+            .unwrap()
+        };
+        let owner = r#"[{apiVersion: autovpa.dev/v1, kind: ClusterAutoVPA, name: office, uid: "1", controller: true}]"#;
+        let generated = vpa("nginx-generated", "nginx", owner);
+        let handwritten = vpa("nginx-by-hand", "nginx", "[]");
+        let other = vpa("nginx-deployment-vpa", "redis", "[]");
+        let decide = |policy, vpas: &[&VerticalPodAutoscaler]| {
+            let adoption = adoption(
+                &policy,
+                vpas.iter().copied(),
+                "Deployment",
+                "nginx",
+                "nginx-deployment-vpa",
+            );
+            match adoption {
+                Adoption::None => "none".to_string(),
+                Adoption::Skip(reason) => format!("skip: {}", reason),
+                Adoption::Adopt(vpa) => format!("adopt {}", vpa.name_any()),
+                Adoption::Ambiguous(reason) => format!("ambiguous: {}", reason),
+                Adoption::Replace(vpas) => {
+                    let names: Vec<String> = vpas.iter().map(|v| v.name_any()).collect();
+                    format!("replace {}", names.join(", "))
+                }
+            }
+        };
+
+        for policy in [AdoptionPolicy::Skip, AdoptionPolicy::Adopt, AdoptionPolicy::Replace] {
+            assert_eq!(decide(policy, &[&generated]), "none");
+        }
+        assert_eq!(
+            decide(AdoptionPolicy::Skip, &[&handwritten]),
+            "skip: vpa nginx-by-hand already exists"
+        );
+        assert_eq!(
+            decide(AdoptionPolicy::Adopt, &[&handwritten, &generated]),
+            "adopt nginx-by-hand"
+        );
+        assert_eq!(
+            decide(AdoptionPolicy::Replace, &[&handwritten, &generated]),
+            "replace nginx-by-hand"
+        );
+
+        // Adopting one of several VPAs of the workload would leave the others fighting over it.
+        let handwritten_too = vpa("nginx-by-hand-too", "nginx", "[]");
+        assert_eq!(
+            decide(AdoptionPolicy::Adopt, &[&handwritten, &handwritten_too]),
+            "ambiguous: vpas nginx-by-hand, nginx-by-hand-too all target the workload"
+        );
+
+        // The generated name is taken by the VPA of another workload, which is never touched.
+        let taken = "skip: vpa nginx-deployment-vpa already exists for another target";
+        assert_eq!(decide(AdoptionPolicy::Adopt, &[&other]), taken);
+        assert_eq!(decide(AdoptionPolicy::Replace, &[&handwritten, &other]), taken);
+    }
+
+    #[test]
+    fn test_generated_name() {
+        let mut template = get_test_vpa_gen("a").spec.vpa_template.clone().unwrap();
+        let name = |template: &_, adopted| {
+            generated_name(adopted, template, "Deployment", "default", "nginx")
+        };
+        assert_eq!(name(&template, None).unwrap(), "nginx-deployment-vpa");
+        template.name_template = Some("{namespace}-{name}".to_string());
+        assert_eq!(name(&template, None).unwrap(), "default-nginx");
+        let adopted = get_expected_vpa();
+        assert_eq!(name(&template, Some(&adopted)).unwrap(), "nginx-vpa");
+        template.name_template = Some("---".to_string());
+        assert!(name(&template, None).is_err());
+    }
+
+    #[test]
+    fn test_target_kinds() {
+        let registered = vec![
+            GroupVersionKind::gvk("apps", "v1", "Deployment"),
+            GroupVersionKind::gvk("apps", "v1", "StatefulSet"),
+            GroupVersionKind::gvk("argoproj.io", "v1alpha1", "Rollout"),
+        ];
+        let default = get_test_vpa_gen("default");
+        let kinds =
+            |gvks: Vec<GroupVersionKind>| gvks.into_iter().map(|g| g.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds(target_gvks(&default, &registered).unwrap()),
+            ["Deployment", "StatefulSet"]
+        );
+
+        let mut rollouts = get_test_vpa_gen("rollouts");
+        rollouts.spec.target_kinds = Some(vec!["Rollout".to_string()]);
+        assert_eq!(kinds(target_gvks(&rollouts, &registered).unwrap()), ["Rollout"]);
+        assert_eq!(
+            kinds(watched_gvks([&rollouts as &dyn AutoVPAResource], &registered)),
+            ["Rollout"]
+        );
+        assert_eq!(
+            kinds(watched_gvks([&default as &dyn AutoVPAResource, &rollouts], &registered)),
+            ["Deployment", "StatefulSet", "Rollout"]
+        );
+
+        rollouts.spec.target_kinds = Some(vec!["CloneSet".to_string()]);
+        assert_eq!(target_gvks(&rollouts, &registered).unwrap_err().reason(), "UnknownKind");
+        rollouts.spec.target_kinds = None;
+        rollouts.spec.name_patterns = Some(serde_yaml::from_str("{include: ['regex:(']}").unwrap());
+        assert_eq!(parse_selection(&rollouts, &registered).unwrap_err().reason(), "InvalidPattern");
+    }
+
+    #[test]
+    fn test_vpa_template() {
+        let mut autovpa = get_test_vpa_gen("default");
+        let template = autovpa.spec.vpa_template.take().unwrap();
+        let mut config = Config::default();
+        assert!(matches!(vpa_template(&autovpa, &config), Err(Error::MissingTemplate)));
+
+        config.default_template = Some(template.clone());
+        assert_eq!(vpa_template(&autovpa, &config).unwrap(), template);
+
+        let mut own = template.clone();
+        own.name_template = Some("{name}".to_string());
+        autovpa.spec.vpa_template = Some(own.clone());
+        assert_eq!(vpa_template(&autovpa, &config).unwrap(), own);
+
+        // Without `{name}`, every target would get the same VPA.
+        own.name_template = Some("{kind}-vpa".to_string());
+        autovpa.spec.vpa_template = Some(own);
+        assert!(matches!(vpa_template(&autovpa, &config), Err(Error::InvalidNameTemplate(_))));
+    }
+
+    #[test]
+    fn test_is_controlled_by_target() {
+        let registered = vec![
+            GroupVersionKind::gvk("apps", "v1", "Deployment"),
+            GroupVersionKind::gvk("batch", "v1", "CronJob"),
+        ];
+        let job: DynamicObject = serde_yaml::from_str(
+            r#"
+apiVersion: batch/v1
+kind: Job
+metadata:
+  name: backup-28000000
+  ownerReferences:
+  - apiVersion: batch/v1
+    kind: CronJob
+    name: backup
+    uid: 6acf7614-e0de-492e-b4a5-77a51ef27c92
+    controller: true
+        "#,
+        )
+        .unwrap();
+        assert!(is_controlled_by_target(&job, &registered));
+        assert!(!is_controlled_by_target(&job, &registered[..1]));
+
+        let mut orphan = job.clone();
+        orphan.metadata.owner_references = None;
+        assert!(!is_controlled_by_target(&orphan, &registered));
+    }
+
+    #[test]
+    fn test_is_excluded() {
+        let mut autovpa = get_test_vpa_gen("office");
+        let mut target: DynamicObject = serde_yaml::from_str(
+            r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: santa
+  namespace: ali-office-test
+  labels:
+    app: santa
+    tier: batch
+        "#,
+        )
+        .unwrap();
+        assert!(!is_excluded(&autovpa, &target));
+
+        target.annotations_mut().insert("autovpa.dev/ignore".to_string(), "false".to_string());
+        assert!(!is_excluded(&autovpa, &target));
+        target.annotations_mut().insert("autovpa.dev/ignore".to_string(), "true".to_string());
+        assert!(is_excluded(&autovpa, &target));
+
+        target.annotations_mut().clear();
+        autovpa.spec.exclude_selector =
+            Some(serde_yaml::from_str("matchLabels: {tier: web}").unwrap());
+        assert!(!is_excluded(&autovpa, &target));
+        autovpa.spec.exclude_selector =
+            Some(serde_yaml::from_str("matchLabels: {tier: batch}").unwrap());
+        assert!(is_excluded(&autovpa, &target));
+    }
+
+    #[test]
+    fn test_name_patterns() {
+        let patterns: NamePatterns =
+            serde_yaml::from_str("{include: [team-*, 'regex:^api-v[12]$'], exclude: [team-test]}")
+                .unwrap();
+        assert!(patterns.validate().is_ok());
+        assert!(patterns.matches("team-payments"));
+        assert!(patterns.matches("api-v2"));
+        assert!(!patterns.matches("team-test"));
+        assert!(!patterns.matches("api-v3"));
+        // Each pattern is compiled once, on its first match.
+        assert!(patterns.include.iter().all(|p| p.compiled.get().is_some()));
+        assert_eq!(
+            serde_json::to_value(&patterns).unwrap(),
+            serde_json::json!({"include": ["team-*", "regex:^api-v[12]$"], "exclude": ["team-test"]})
+        );
+
+        let invalid: NamePatterns = serde_yaml::from_str("{include: ['regex:(']}").unwrap();
+        assert!(invalid.validate().is_err());
+        assert!(!invalid.matches("anything"));
+    }
+
+    #[test]
+    fn test_namespaced_selection() {
+        let mut auto_vpa: AutoVPA = serde_yaml::from_str(
             r#"
 apiVersion: autovpa.dev/v1
 kind: AutoVPA
 metadata:
-  name: {}
+  name: santa
+  namespace: payments
+spec:
+  namespaceNames:
+  - payments
+  objectSelector:
+    matchLabels:
+      app: santa
+  vpaTemplate:
+    template: {}
+        "#,
+        )
+        .unwrap();
+        let registered = vec![GroupVersionKind::gvk("apps", "v1", "Deployment")];
+        let err = parse_selection(&auto_vpa, &registered).unwrap_err();
+        assert_eq!(err.reason(), "NamespacedSelection");
+        auto_vpa.spec.namespace_names = None;
+        assert!(parse_selection(&auto_vpa, &registered).is_ok());
+
+        let namespaces = kube::runtime::reflector::store().0;
+        let mut target: DynamicObject = serde_yaml::from_str(
+            r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: santa
+  namespace: payments
+  labels:
+    app: santa
+        "#,
+        )
+        .unwrap();
+        assert!(selects_target(&auto_vpa, &target, &namespaces));
+        target.metadata.namespace = Some("checkout".to_string());
+        assert!(!selects_target(&auto_vpa, &target, &namespaces));
+    }
+
+    #[test]
+    fn test_namespaced_crd() {
+        use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+        use kube::CustomResourceExt;
+
+        let spec_schema = |crd: CustomResourceDefinition| {
+            let schema = crd.spec.versions[0].schema.clone().unwrap().open_api_v3_schema.unwrap();
+            schema.properties.unwrap()["spec"].clone()
+        };
+        // The API server rejects the namespace selection of an AutoVPA at admission.
+        let rules = spec_schema(AutoVPA::crd()).x_kubernetes_validations.unwrap();
+        assert!(rules[0].rule.contains("!has(self.namespaceSelector)"));
+        assert!(spec_schema(ClusterAutoVPA::crd()).x_kubernetes_validations.is_none());
+    }
+
+    #[test]
+    fn test_invalid_status() {
+        use kube::CustomResourceExt;
+
+        let status = invalid_status(&[], Some(1));
+        for crd in [AutoVPA::crd(), ClusterAutoVPA::crd()] {
+            let schema = crd.spec.versions[0].schema.clone().unwrap().open_api_v3_schema.unwrap();
+            let status_schema = &schema.properties.unwrap()["status"];
+            // The patch is rejected if it misses a required field, as on an AutoVPA without a status yet.
+            for field in status_schema.required.iter().flatten() {
+                assert!(status["status"].get(field).is_some(), "{} is required", field);
+            }
+        }
+    }
+
+    #[test]
+    fn test_awaits_namespaces() {
+        let namespaces = kube::runtime::reflector::store().0;
+        let cluster_wide = Options::default().settings();
+        let watch_namespaces = vec!["payments".to_string()];
+        let namespaced = Options { watch_namespaces, ..Default::default() }.settings();
+
+        let mut cluster_auto_vpa = get_test_vpa_gen("santa");
+        assert!(!awaits_namespaces(&cluster_auto_vpa, &cluster_wide, &namespaces));
+        cluster_auto_vpa.spec.namespace_selector = Some(LabelSelector::default());
+        assert!(awaits_namespaces(&cluster_auto_vpa, &cluster_wide, &namespaces));
+
+        // Namespaces are not watched in namespaced mode, so the selection is reported invalid instead of waiting.
+        let auto_vpa: AutoVPA = serde_yaml::from_str(
+            r#"
+apiVersion: autovpa.dev/v1
+kind: AutoVPA
+metadata:
+  name: santa
+  namespace: payments
 spec:
   namespaceSelector:
+    matchLabels:
+      team: payments
+  vpaTemplate:
+    template: {}
+        "#,
+        )
+        .unwrap();
+        assert!(!awaits_namespaces(&auto_vpa, &namespaced, &namespaces));
+        let registered = vec![GroupVersionKind::gvk("apps", "v1", "Deployment")];
+        let err = parse_selection(&auto_vpa, &registered).unwrap_err();
+        assert_eq!(err.reason(), "NamespacedSelection");
+    }
+
+    #[test]
+    fn test_set_condition() {
+        let mut conditions = vec![];
+        set_condition(&mut conditions, "Ready", true, "Reconciled", String::new(), Some(1));
+        let transitioned = conditions[0].last_transition_time.clone();
+
+        set_condition(&mut conditions, "Ready", true, "Reconciled", "2 of 2".into(), Some(2));
+        assert_eq!(1, conditions.len());
+        assert_eq!(transitioned, conditions[0].last_transition_time);
+        assert_eq!(Some(2), conditions[0].observed_generation);
+
+        set_condition(&mut conditions, "Degraded", false, "Reconciled", String::new(), Some(2));
+        assert_eq!(
+            vec!["Degraded", "Ready"],
+            conditions.iter().map(|c| &c.type_).collect::<Vec<_>>()
+        );
+        assert_eq!("True", conditions[1].status);
+    }
+
+    #[test]
+    fn test_is_drifted() {
+        let mut vpa: VerticalPodAutoscaler = serde_yaml::from_str(
+            r#"
+apiVersion: autoscaling.k8s.io/v1
+kind: VerticalPodAutoscaler
+metadata:
+  name: nginx-deployment-vpa
+spec:
+  updatePolicy:
+    updateMode: Auto
+        "#,
+        )
+        .unwrap();
+        assert!(!is_drifted(&vpa));
+
+        let hash = spec_hash(&vpa.spec);
+        vpa.annotations_mut().insert(SPEC_HASH_ANNOTATION.to_string(), hash);
+        assert!(!is_drifted(&vpa));
+
+        vpa.spec.update_policy.as_mut().unwrap().min_replicas = Some(2);
+        assert!(is_drifted(&vpa));
+    }
+
+    #[test]
+    fn test_prune_selection() {
+        let vpa = |name: &str, uid: &str| -> VerticalPodAutoscaler {
+            serde_yaml::from_str(&format!(
+                r#"
+apiVersion: autoscaling.k8s.io/v1
+kind: VerticalPodAutoscaler
+metadata:
+  name: {}
+  namespace: default
+  ownerReferences:
+  - apiVersion: autovpa.dev/v1
+    kind: ClusterAutoVPA
+    name: office
+    uid: {}
+    controller: true
+spec:
+  updatePolicy:
+    updateMode: Auto
+        "#,
+                name, uid
+            ))
+            .unwrap()
+        };
+        let vpas = vec![vpa("kept", "office"), vpa("stale", "office"), vpa("other", "shop")];
+        let matched = HashSet::from([("default".to_string(), "kept".to_string())]);
+        let stale: Vec<String> =
+            stale_vpas(vpas, "office", &matched).iter().map(|v| v.name_any()).collect();
+        assert_eq!(stale, ["stale"]);
+
+        let mut stale = vpa("stale", "office");
+        assert_eq!(prune_action(&PrunePolicy::Delete, &stale), Some(PlannedAction::Delete));
+        assert_eq!(prune_action(&PrunePolicy::Orphan, &stale), Some(PlannedAction::Orphan));
+        assert_eq!(prune_action(&PrunePolicy::SetOff, &stale), Some(PlannedAction::SetOff));
+        stale.spec.update_policy.as_mut().unwrap().update_mode =
+            Some(VerticalPodAutoscalerUpdateMode::Off);
+        assert_eq!(prune_action(&PrunePolicy::SetOff, &stale), None);
+        assert_eq!(prune_action(&PrunePolicy::Delete, &stale), Some(PlannedAction::Delete));
+    }
+
+    #[test]
+    fn test_planned_changes() {
+        let existing = get_expected_vpa();
+        assert_eq!(planned_action(None, &existing), Some(PlannedAction::Create));
+        assert_eq!(planned_action(Some(&existing), &existing), None);
+
+        let mut applied = existing.clone();
+        applied.spec.update_policy.as_mut().unwrap().update_mode =
+            Some(VerticalPodAutoscalerUpdateMode::Off);
+        assert_eq!(planned_action(Some(&existing), &applied), Some(PlannedAction::Update));
+        let mut applied = existing.clone();
+        applied.annotations_mut().insert(SPEC_HASH_ANNOTATION.to_string(), "0".to_string());
+        assert_eq!(planned_action(Some(&existing), &applied), Some(PlannedAction::Update));
+
+        // Stale VPAs are planned by their prune policy.
+        assert_eq!(prune_action(&PrunePolicy::Delete, &existing), Some(PlannedAction::Delete));
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(LogFormat::Json, "json".parse().unwrap());
+        assert_eq!(LogFormat::Pretty, "pretty".parse().unwrap());
+        assert!("JSON".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_watch_namespaces() {
+        let settings = Options::default().settings();
+        assert!(settings.cluster_wide());
+
+        let watch_namespaces = vec!["team-a".into(), " team-b ".into(), "".into()];
+        let settings = Options { watch_namespaces, ..Default::default() }.settings();
+        assert!(!settings.cluster_wide());
+        assert_eq!(settings.namespaces, vec!["team-a", "team-b"]);
+    }
+
+    #[tokio::test]
+    async fn test_counted() {
+        let metrics = Arc::new(Metrics::default());
+        let events = vec![
+            Ok(watcher::Event::Restarted(vec![get_test_workload()])),
+            Ok(watcher::Event::Applied(get_test_workload())),
+            Err(watcher::Error::NoResourceVersion),
+            Ok(watcher::Event::Restarted(vec![])),
+        ];
+        let events: Vec<_> = counted(stream::iter(events), &metrics, "Deployment").collect().await;
+        assert_eq!(events.len(), 4);
+
+        // The initial list is not a restart.
+        let rendered = metrics.render();
+        assert!(rendered.contains("autoscaler_genie_watch_restarts_total{kind=\"Deployment\"} 1\n"));
+        assert!(rendered.contains("autoscaler_genie_watch_errors_total{kind=\"Deployment\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_fanout() {
+        let events = vec![
+            Ok(watcher::Event::Restarted(vec![get_test_workload()])),
+            Err(watcher::Error::NoResourceVersion),
+            Ok(watcher::Event::Deleted(get_test_workload())),
+        ];
+        let (fanout, forward) = Fanout::new(stream::iter(events));
+        let (first, second) = (fanout.subscribe(), fanout.subscribe());
+        let dropped = fanout.subscribe();
+        drop(dropped);
+        forward.await;
+
+        // Every subscriber sees each touched object, the errors are left out.
+        drop(fanout);
+        for subscriber in [first, second] {
+            let objects: Vec<_> = subscriber.collect().await;
+            assert_eq!(objects.len(), 2);
+            assert!(objects
+                .iter()
+                .all(|o| o.as_ref().is_ok_and(|o| o.name_any() == "nginx-deployment")));
+        }
+    }
+
+    fn get_test_vpa_gen(name: &str) -> ClusterAutoVPA {
+        let test_yaml = format!(
+            r#"
+apiVersion: autovpa.dev/v1
+kind: ClusterAutoVPA
+metadata:
+  name: {}
+spec:
+  namespaceNames:
   - ali-office-test
   objectSelector:
     matchLabels:
@@ -1,6 +0,0 @@
-use autoscaler_genie::AutoVPA;
-use kube::CustomResourceExt;
-
-fn main() {
-    print!("{}", serde_yaml::to_string(&AutoVPA::crd()).unwrap())
-}
@@ -0,0 +1,191 @@
+use crate::controller::{AutoVPA, AutoVPASpec, ClusterAutoVPA, PlannedChange};
+use crate::vpa::VerticalPodAutoscalerSpec;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
+use kube::runtime::reflector::Store;
+use kube::ResourceExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, RwLock};
+
+/// Triggers kept per AutoVPA, the oldest ones are dropped first.
+const MAX_TRIGGERS: usize = 20;
+
+/// What the controller currently believes about each AutoVPA, served on `/debug/autovpas`.
+/// AutoVPAs are keyed `AutoVPA/<namespace>/<name>` or `ClusterAutoVPA/<name>`.
+#[derive(Default)]
+pub struct Diagnostics {
+    stores: RwLock<Option<(Store<AutoVPA>, Store<ClusterAutoVPA>)>>,
+    reconciles: Mutex<BTreeMap<String, Reconcile>>,
+    /// Events which triggered a reconcile of each AutoVPA since its last reconcile started.
+    pending: Mutex<BTreeMap<String, Vec<String>>>,
+}
+
+/// The last reconcile of an AutoVPA.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reconcile {
+    /// Targets the AutoVPA generates a VPA for, as `Kind/namespace/name`.
+    pub matched_targets: Vec<String>,
+    pub vpas: Vec<RenderedVPA>,
+    /// Changes made, or planned in dry-run, to the VPAs.
+    pub plan: Vec<PlannedChange>,
+    /// Objects whose events triggered the last reconcile, none when the AutoVPA itself changed or was requeued.
+    pub triggers: Vec<String>,
+    pub last_reconcile_time: Option<Time>,
+    pub last_error: Option<String>,
+    pub last_error_time: Option<Time>,
+}
+
+/// A VPA as rendered for a target, before it is applied.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenderedVPA {
+    pub namespace: String,
+    pub name: String,
+    pub target: String,
+    pub spec: VerticalPodAutoscalerSpec,
+}
+
+/// An AutoVPA of the stores together with its last reconcile.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoVPAState {
+    pub key: String,
+    pub generation: Option<i64>,
+    pub spec: AutoVPASpec,
+    #[serde(flatten)]
+    pub reconcile: Reconcile,
+}
+
+impl Diagnostics {
+    /// Serves the AutoVPAs of `autovpas` and `cluster_autovpas`, the stores of the running controllers.
+    pub fn set_stores(&self, autovpas: Store<AutoVPA>, cluster_autovpas: Store<ClusterAutoVPA>) {
+        *self.stores.write().unwrap() = Some((autovpas, cluster_autovpas));
+    }
+
+    /// Records that `trigger` caused a reconcile of `autovpa`.
+    pub fn triggered(&self, autovpa: String, trigger: String) {
+        let mut pending = self.pending.lock().unwrap();
+        let triggers = pending.entry(autovpa).or_default();
+        if !triggers.contains(&trigger) {
+            triggers.push(trigger);
+        }
+        if triggers.len() > MAX_TRIGGERS {
+            triggers.remove(0);
+        }
+    }
+
+    /// Records that a reconcile of `autovpa` starts, triggered by the events recorded since the previous one.
+    pub fn reconciling(&self, autovpa: String) {
+        let triggers = self.pending.lock().unwrap().remove(&autovpa).unwrap_or_default();
+        self.reconciles.lock().unwrap().entry(autovpa).or_default().triggers = triggers;
+    }
+
+    /// Records a reconcile of `autovpa`, which clears the error of an earlier one. A reconcile failing afterwards
+    /// records its error with `failed`.
+    pub fn reconciled(
+        &self,
+        autovpa: String,
+        matched_targets: Vec<String>,
+        vpas: Vec<RenderedVPA>,
+        plan: Vec<PlannedChange>,
+    ) {
+        let mut reconciles = self.reconciles.lock().unwrap();
+        let reconcile = reconciles.entry(autovpa).or_default();
+        reconcile.matched_targets = matched_targets;
+        reconcile.vpas = vpas;
+        reconcile.plan = plan;
+        reconcile.last_reconcile_time = Some(Time(Utc::now()));
+        reconcile.last_error = None;
+        reconcile.last_error_time = None;
+    }
+
+    pub fn failed(&self, autovpa: String, error: String) {
+        let mut reconciles = self.reconciles.lock().unwrap();
+        let reconcile = reconciles.entry(autovpa).or_default();
+        reconcile.last_error = Some(error);
+        reconcile.last_error_time = Some(Time(Utc::now()));
+    }
+
+    pub fn last_reconcile(&self, autovpa: &str) -> Option<Reconcile> {
+        self.reconciles.lock().unwrap().get(autovpa).cloned()
+    }
+
+    /// Drops the state of the AutoVPAs that no longer exist.
+    pub fn retain_autovpas(&self, exists: impl Fn(&str) -> bool) {
+        self.reconciles.lock().unwrap().retain(|autovpa, _| exists(autovpa));
+        self.pending.lock().unwrap().retain(|autovpa, _| exists(autovpa));
+    }
+
+    /// The AutoVPAs of the stores, empty on replicas standing by for the leader lease.
+    pub fn autovpas(&self) -> Vec<AutoVPAState> {
+        let Some((autovpas, cluster_autovpas)) = self.stores.read().unwrap().clone() else {
+            return vec![];
+        };
+        let reconciles = self.reconciles.lock().unwrap();
+        let state = |key: String, generation: Option<i64>, spec: &AutoVPASpec| AutoVPAState {
+            reconcile: reconciles.get(&key).cloned().unwrap_or_default(),
+            key,
+            generation,
+            spec: spec.clone(),
+        };
+        let mut states: Vec<AutoVPAState> = autovpas
+            .state()
+            .iter()
+            .map(|g| {
+                let key = format!("AutoVPA/{}/{}", g.namespace().unwrap_or_default(), g.name_any());
+                state(key, g.metadata.generation, &g.spec)
+            })
+            .chain(cluster_autovpas.state().iter().map(|g| {
+                let key = format!("ClusterAutoVPA/{}", g.name_any());
+                state(key, g.metadata.generation, &g.spec)
+            }))
+            .collect();
+        states.sort_by(|a, b| a.key.cmp(&b.key));
+        states
+    }
+
+    pub fn autovpa(&self, key: &str) -> Option<AutoVPAState> {
+        self.autovpas().into_iter().find(|g| g.key == key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Diagnostics;
+
+    #[test]
+    fn test_triggers() {
+        let diagnostics = Diagnostics::default();
+        let autovpa = "ClusterAutoVPA/nginx";
+        for i in 0..30 {
+            diagnostics.triggered(autovpa.to_string(), format!("Deployment default/nginx-{}", i));
+        }
+        diagnostics.triggered(autovpa.to_string(), "Deployment default/nginx-29".to_string());
+        diagnostics.reconciling(autovpa.to_string());
+        let triggers = diagnostics.reconciles.lock().unwrap()[autovpa].triggers.clone();
+        assert_eq!(20, triggers.len());
+        assert_eq!("Deployment default/nginx-10", triggers[0]);
+
+        diagnostics.reconciling(autovpa.to_string());
+        assert!(diagnostics.reconciles.lock().unwrap()[autovpa].triggers.is_empty());
+    }
+
+    #[test]
+    fn test_last_error() {
+        let diagnostics = Diagnostics::default();
+        let autovpa = "ClusterAutoVPA/nginx";
+        diagnostics.failed(autovpa.to_string(), "vpa apply failed".to_string());
+        let reconcile = diagnostics.last_reconcile(autovpa).unwrap();
+        assert_eq!(Some("vpa apply failed".to_string()), reconcile.last_error);
+        assert!(reconcile.last_error_time.is_some());
+
+        // A later successful reconcile no longer reports the error.
+        diagnostics.reconciled(autovpa.to_string(), vec![], vec![], vec![]);
+        let reconcile = diagnostics.last_reconcile(autovpa).unwrap();
+        assert!(reconcile.last_reconcile_time.is_some());
+        assert_eq!(None, reconcile.last_error);
+        assert_eq!(None, reconcile.last_error_time);
+    }
+}
@@ -0,0 +1,219 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::Client;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Controller the events are reported by.
+const REPORTER: &str = "autoscaler-genie";
+/// Set through the downward API, tells the replicas reporting events apart.
+const POD_NAME_ENV: &str = "POD_NAME";
+
+/// Above this many events of one reason in a reconcile, a single event on the AutoVPA sums them up.
+const MAX_EVENTS_PER_REASON: usize = 10;
+/// An identical event is published at most once per window.
+const DEDUP_WINDOW: Duration = Duration::from_secs(600);
+/// Objects named in the note of a summed up event.
+const SUMMARY_EXAMPLES: usize = 5;
+
+type EventKey = (String, String, String);
+
+/// Publishes the events collected in an [`EventBatch`], dropping the ones published recently.
+#[derive(Clone)]
+pub struct EventRecorder {
+    client: Client,
+    reporter: Reporter,
+    published: Arc<Mutex<Published>>,
+}
+
+/// An event ready to be published.
+struct PendingEvent {
+    regarding: ObjectReference,
+    type_: EventType,
+    reason: String,
+    action: String,
+    note: String,
+}
+
+/// When each event was published, to drop the identical ones within [`DEDUP_WINDOW`].
+#[derive(Default)]
+struct Published(HashMap<EventKey, Instant>);
+
+impl Published {
+    /// Whether `event` is due at `now`, recording it as published if so.
+    fn is_due(&mut self, event: &PendingEvent, now: Instant) -> bool {
+        self.0.retain(|_, at| now.duration_since(*at) < DEDUP_WINDOW);
+        let key = (describe(&event.regarding), event.reason.clone(), event.note.clone());
+        match self.0.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+impl EventRecorder {
+    pub fn new(client: Client) -> Self {
+        let reporter =
+            Reporter { controller: REPORTER.into(), instance: std::env::var(POD_NAME_ENV).ok() };
+        Self { client, reporter, published: Default::default() }
+    }
+
+    /// Publishes the events of `batch`. Reasons with more than [`MAX_EVENTS_PER_REASON`] events are summed up
+    /// in a single event on `summary`, the AutoVPA the batch was collected for.
+    pub async fn publish(&self, summary: &ObjectReference, batch: EventBatch) {
+        for event in collapse(summary, batch) {
+            if !self.published.lock().unwrap().is_due(&event, Instant::now()) {
+                continue;
+            }
+            let PendingEvent { regarding, type_, reason, action, note } = event;
+            let recorder = Recorder::new(self.client.clone(), self.reporter.clone(), regarding);
+            let event =
+                Event { type_, reason: reason.clone(), note: Some(note), action, secondary: None };
+            if let Err(err) = recorder.publish(event).await {
+                warn!(reason, error = %err, "publish event failed");
+            }
+        }
+    }
+}
+
+/// The events of `batch`, with the reasons of more than [`MAX_EVENTS_PER_REASON`] events summed up on `summary`.
+fn collapse(summary: &ObjectReference, batch: EventBatch) -> Vec<PendingEvent> {
+    let mut pending = vec![];
+    for ((type_, reason, action), events) in batch.events {
+        if events.len() <= MAX_EVENTS_PER_REASON {
+            for (regarding, note) in events {
+                let (reason, action) = (reason.clone(), action.clone());
+                pending.push(PendingEvent { regarding, type_, reason, action, note });
+            }
+            continue;
+        }
+        let examples: Vec<String> =
+            events.iter().take(SUMMARY_EXAMPLES).map(|(r, _)| describe(r)).collect();
+        let note = format!("{} for {} objects, e.g. {}", reason, events.len(), examples.join(", "));
+        pending.push(PendingEvent { regarding: summary.clone(), type_, reason, action, note });
+    }
+    pending
+}
+
+/// Events collected during a reconcile, grouped by reason so they can be summed up.
+#[derive(Default)]
+pub struct EventBatch {
+    events: HashMap<(EventType, String, String), Vec<(ObjectReference, String)>>,
+}
+
+impl EventBatch {
+    pub fn normal(&mut self, regarding: ObjectReference, reason: &str, action: &str, note: String) {
+        self.push(regarding, EventType::Normal, reason, action, note)
+    }
+
+    pub fn warning(
+        &mut self,
+        regarding: ObjectReference,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) {
+        self.push(regarding, EventType::Warning, reason, action, note)
+    }
+
+    fn push(
+        &mut self,
+        regarding: ObjectReference,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) {
+        self.events
+            .entry((type_, reason.to_string(), action.to_string()))
+            .or_default()
+            .push((regarding, note));
+    }
+}
+
+fn describe(reference: &ObjectReference) -> String {
+    let kind = reference.kind.as_deref().unwrap_or_default();
+    let name = reference.name.as_deref().unwrap_or_default();
+    match &reference.namespace {
+        Some(namespace) => format!("{}/{}/{}", kind, namespace, name),
+        None => format!("{}/{}", kind, name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{collapse, EventBatch, PendingEvent, Published, DEDUP_WINDOW};
+    use k8s_openapi::api::core::v1::ObjectReference;
+    use std::time::{Duration, Instant};
+
+    fn reference(kind: &str, name: &str) -> ObjectReference {
+        ObjectReference {
+            kind: Some(kind.to_string()),
+            namespace: Some("default".to_string()),
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_collapse() {
+        let summary = reference("AutoVPA", "office");
+        let mut batch = EventBatch::default();
+        for i in 0..11 {
+            let note = format!("Created VPA default/nginx-{}", i);
+            batch.normal(
+                reference("Deployment", &format!("nginx-{}", i)),
+                "VPACreated",
+                "ApplyVPA",
+                note,
+            );
+        }
+        for i in 0..10 {
+            let note = format!("Apply VPA default/redis-{} failed", i);
+            batch.warning(
+                reference("StatefulSet", &format!("redis-{}", i)),
+                "ApplyFailed",
+                "ApplyVPA",
+                note,
+            );
+        }
+
+        let events = collapse(&summary, batch);
+        assert_eq!(events.len(), 11);
+        assert_eq!(events.iter().filter(|e| e.reason == "ApplyFailed").count(), 10);
+        let created: Vec<&PendingEvent> =
+            events.iter().filter(|e| e.reason == "VPACreated").collect();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].regarding, summary);
+        assert!(created[0]
+            .note
+            .starts_with("VPACreated for 11 objects, e.g. Deployment/default/nginx-"));
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let event = |note: &str| {
+            let mut batch = EventBatch::default();
+            batch.warning(
+                reference("Deployment", "nginx"),
+                "ApplyFailed",
+                "ApplyVPA",
+                note.to_string(),
+            );
+            collapse(&reference("AutoVPA", "office"), batch).remove(0)
+        };
+        let mut published = Published::default();
+        let start = Instant::now();
+        assert!(published.is_due(&event("conflict"), start));
+        assert!(!published.is_due(&event("conflict"), start + Duration::from_secs(300)));
+        assert!(published.is_due(&event("timeout"), start + Duration::from_secs(300)));
+        // The window runs from the first publication, repeats don't extend it.
+        assert!(published.is_due(&event("conflict"), start + DEDUP_WINDOW));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Health of the controller, as reported by the liveness and readiness probes.
+pub struct Health {
+    crds_checked: AtomicBool,
+    /// Stores of the running controller which have not listed their objects yet.
+    syncing: Mutex<BTreeSet<&'static str>>,
+    /// Since when the watches and the controller stream only fail, if they do.
+    failing_since: Mutex<Option<Instant>>,
+    /// When the controller last reconciled an AutoVPA, whether it succeeded or not.
+    last_reconcile: Mutex<Instant>,
+    /// Period the controller resyncs its AutoVPAs at, none unless it has valid AutoVPAs to resync.
+    resync: Mutex<Option<Duration>>,
+    stall_threshold: Duration,
+}
+
+impl Health {
+    pub fn new(stall_threshold: Duration) -> Self {
+        Self {
+            crds_checked: AtomicBool::new(false),
+            syncing: Default::default(),
+            failing_since: Default::default(),
+            last_reconcile: Mutex::new(Instant::now()),
+            resync: Default::default(),
+            stall_threshold,
+        }
+    }
+
+    /// Records that the AutoVPA and VPA CRDs are installed.
+    pub fn crds_checked(&self) {
+        self.crds_checked.store(true, Ordering::Relaxed);
+    }
+
+    /// Records that the controller (re)starts with `stores`, which are not ready until they are listed.
+    pub fn syncing(&self, stores: &[&'static str]) {
+        *self.syncing.lock().unwrap() = stores.iter().copied().collect();
+    }
+
+    pub fn synced(&self, store: &'static str) {
+        self.syncing.lock().unwrap().remove(store);
+    }
+
+    /// Records that a watch or the controller stream made progress.
+    pub fn progressed(&self) {
+        *self.failing_since.lock().unwrap() = None;
+    }
+
+    /// Records that a watch or the controller stream failed.
+    pub fn failed(&self) {
+        self.failing_since.lock().unwrap().get_or_insert_with(Instant::now);
+    }
+
+    /// Records that the controller reconciled an AutoVPA, whether it succeeded or not.
+    pub fn reconciled(&self) {
+        *self.last_reconcile.lock().unwrap() = Instant::now();
+    }
+
+    /// Records the period the controller resyncs its valid AutoVPAs at, none if it has none or doesn't resync them.
+    /// The controller is expected to reconcile at least once per period from then on.
+    pub fn resyncing(&self, resync: Option<Duration>) {
+        let mut current = self.resync.lock().unwrap();
+        if current.is_none() && resync.is_some() {
+            self.reconciled();
+        }
+        *current = resync;
+    }
+
+    /// Ready once the CRDs are installed and the stores of the running controller, if any, are listed.
+    /// Replicas standing by for the leader lease have no stores, so they are ready as well.
+    pub fn readiness(&self) -> Result<(), String> {
+        if !self.crds_checked.load(Ordering::Relaxed) {
+            return Err("crds not checked yet".into());
+        }
+        let syncing = self.syncing.lock().unwrap();
+        if !syncing.is_empty() {
+            let stores: Vec<&str> = syncing.iter().copied().collect();
+            return Err(format!("stores not synced yet: {}", stores.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Live unless the watches and the controller stream stalled, only failing for longer than the threshold,
+    /// or the controller resyncing its AutoVPAs did not reconcile any for longer than both the resync period
+    /// and the threshold.
+    pub fn liveness(&self) -> Result<(), String> {
+        if let Some(since) = *self.failing_since.lock().unwrap() {
+            if since.elapsed() > self.stall_threshold {
+                return Err(format!("controller stalled for {:?}", since.elapsed()));
+            }
+        }
+        if let Some(resync) = *self.resync.lock().unwrap() {
+            let elapsed = self.last_reconcile.lock().unwrap().elapsed();
+            if elapsed > resync.max(self.stall_threshold) {
+                return Err(format!("no reconcile for {:?}", elapsed));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Health;
+    use std::time::Duration;
+
+    #[test]
+    fn test_readiness() {
+        let health = Health::new(Duration::from_secs(300));
+        assert!(health.readiness().is_err());
+        health.crds_checked();
+        assert!(health.readiness().is_ok());
+
+        health.syncing(&["autovpas", "namespaces"]);
+        health.synced("autovpas");
+        assert_eq!(health.readiness(), Err("stores not synced yet: namespaces".to_string()));
+        health.synced("namespaces");
+        assert!(health.readiness().is_ok());
+    }
+
+    #[test]
+    fn test_liveness() {
+        let health = Health::new(Duration::ZERO);
+        assert!(health.liveness().is_ok());
+        health.failed();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(health.liveness().is_err());
+        health.progressed();
+        assert!(health.liveness().is_ok());
+    }
+
+    #[test]
+    fn test_reconcile_stall() {
+        let health = Health::new(Duration::ZERO);
+        // Without valid AutoVPAs to resync, the controller may go without reconciles.
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(health.liveness().is_ok());
+
+        health.resyncing(Some(Duration::from_millis(20)));
+        assert!(health.liveness().is_ok());
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(health.liveness().unwrap_err().starts_with("no reconcile for"));
+        health.reconciled();
+        assert!(health.liveness().is_ok());
+
+        std::thread::sleep(Duration::from_millis(25));
+        health.resyncing(None);
+        assert!(health.liveness().is_ok());
+    }
+}
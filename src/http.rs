@@ -0,0 +1,84 @@
+use crate::diagnostics::Diagnostics;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use actix_web::dev::Server;
+use actix_web::web::{Data, Path};
+use actix_web::{get, middleware, App, HttpResponse, HttpServer, Responder};
+use std::sync::Arc;
+
+#[get("/metrics")]
+async fn render_metrics(metrics: Data<Arc<Metrics>>) -> impl Responder {
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics.render())
+}
+
+#[get("/healthz")]
+async fn healthz(health: Data<Arc<Health>>) -> impl Responder {
+    probe(health.liveness())
+}
+
+#[get("/readyz")]
+async fn readyz(health: Data<Arc<Health>>) -> impl Responder {
+    probe(health.readiness())
+}
+
+#[get("/debug/autovpas")]
+async fn autovpas(diagnostics: Data<Arc<Diagnostics>>) -> impl Responder {
+    HttpResponse::Ok().json(diagnostics.autovpas())
+}
+
+#[get("/debug/autovpas/{name}")]
+async fn cluster_autovpa(
+    diagnostics: Data<Arc<Diagnostics>>,
+    name: Path<String>,
+) -> impl Responder {
+    autovpa_state(&diagnostics, &format!("ClusterAutoVPA/{}", name))
+}
+
+#[get("/debug/autovpas/{namespace}/{name}")]
+async fn autovpa(
+    diagnostics: Data<Arc<Diagnostics>>,
+    path: Path<(String, String)>,
+) -> impl Responder {
+    let (namespace, name) = path.into_inner();
+    autovpa_state(&diagnostics, &format!("AutoVPA/{}/{}", namespace, name))
+}
+
+fn autovpa_state(diagnostics: &Diagnostics, key: &str) -> HttpResponse {
+    match diagnostics.autovpa(key) {
+        Some(state) => HttpResponse::Ok().json(state),
+        None => HttpResponse::NotFound().body(format!("{} not found", key)),
+    }
+}
+
+fn probe(result: Result<(), String>) -> HttpResponse {
+    match result {
+        Ok(()) => HttpResponse::Ok().body("ok"),
+        Err(reason) => HttpResponse::ServiceUnavailable().body(reason),
+    }
+}
+
+/// Serves the metrics, the probes and the diagnostics of the controller on `addr`, on every replica whether it is the leader or not.
+pub fn server(
+    addr: &str,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    diagnostics: Arc<Diagnostics>,
+) -> std::io::Result<Server> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(metrics.clone()))
+            .app_data(Data::new(health.clone()))
+            .app_data(Data::new(diagnostics.clone()))
+            .wrap(middleware::Logger::default().exclude_regex("^/(metrics|healthz|readyz)$"))
+            .service(render_metrics)
+            .service(healthz)
+            .service(readyz)
+            .service(autovpas)
+            .service(cluster_autovpa)
+            .service(autovpa)
+    })
+    .bind(addr)?
+    .shutdown_timeout(5)
+    .run();
+    Ok(server)
+}
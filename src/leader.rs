@@ -0,0 +1,216 @@
+use crate::controller::Error;
+use crate::metrics::Metrics;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{DateTime, Utc};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Name of the Lease the replicas compete for.
+const LEASE_NAME: &str = "autoscaler-genie";
+/// Set to `false` to run without leader election, e.g. with a single replica outside of the cluster.
+const LEADER_ELECTION_ENV: &str = "AUTOVPA_LEADER_ELECTION";
+/// Namespace of the Lease, set through the downward API.
+const POD_NAMESPACE_ENV: &str = "POD_NAMESPACE";
+/// Identity of the replica, set through the downward API.
+const POD_NAME_ENV: &str = "POD_NAME";
+/// Seconds the other replicas wait before taking over a Lease that is no longer renewed.
+const LEASE_DURATION_ENV: &str = "AUTOVPA_LEASE_DURATION";
+/// Seconds the leader keeps trying to renew its Lease before it gives up leading.
+const RENEW_DEADLINE_ENV: &str = "AUTOVPA_RENEW_DEADLINE";
+
+const DEFAULT_NAMESPACE: &str = "kube-system";
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(15);
+const DEFAULT_RENEW_DEADLINE: Duration = Duration::from_secs(10);
+/// How often the Lease is tried to be acquired or renewed.
+const RETRY_PERIOD: Duration = Duration::from_secs(2);
+
+/// Lease-based leader election, so only one replica of the controller runs at a time.
+pub struct LeaderElector {
+    api: Api<Lease>,
+    identity: String,
+    lease_duration: Duration,
+    renew_deadline: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl LeaderElector {
+    /// The leader elector configured through the environment, `None` when leader election is disabled.
+    pub fn from_env(client: Client, metrics: Arc<Metrics>) -> Result<Option<Self>, Error> {
+        if std::env::var(LEADER_ELECTION_ENV).is_ok_and(|v| v.trim() == "false") {
+            return Ok(None);
+        }
+        let namespace =
+            std::env::var(POD_NAMESPACE_ENV).unwrap_or_else(|_| DEFAULT_NAMESPACE.to_string());
+        let identity = std::env::var(POD_NAME_ENV)
+            .unwrap_or_else(|_| format!("{}-{:08x}", LEASE_NAME, rand::random::<u32>()));
+        let lease_duration = env_secs(LEASE_DURATION_ENV, DEFAULT_LEASE_DURATION)?;
+        let renew_deadline = env_secs(RENEW_DEADLINE_ENV, DEFAULT_RENEW_DEADLINE)?;
+        if renew_deadline >= lease_duration || renew_deadline <= RETRY_PERIOD {
+            return Err(Error::InvalidLeaderElection(format!(
+                "renew deadline {:?} must be longer than {:?} and shorter than the lease duration {:?}",
+                renew_deadline, RETRY_PERIOD, lease_duration
+            )));
+        }
+        Ok(Some(Self {
+            api: Api::namespaced(client, &namespace),
+            identity,
+            lease_duration,
+            renew_deadline,
+            metrics,
+        }))
+    }
+
+    /// Waits until this replica holds the Lease.
+    pub async fn acquire(&self) {
+        info!(identity = %self.identity, "waiting for the leader lease");
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => break,
+                Ok(false) => debug!("leader lease is held by another replica"),
+                Err(err) => warn!(error = %err, "acquire leader lease failed"),
+            }
+            tokio::time::sleep(RETRY_PERIOD).await;
+        }
+        info!(identity = %self.identity, "acquired the leader lease");
+        self.metrics.set_leader(true);
+    }
+
+    /// Keeps renewing the Lease, and returns once it could not be renewed within the renew deadline.
+    pub async fn hold(&self) {
+        let mut renewed = Instant::now();
+        loop {
+            tokio::time::sleep(RETRY_PERIOD).await;
+            match tokio::time::timeout(self.renew_deadline, self.try_acquire_or_renew()).await {
+                Ok(Ok(true)) => renewed = Instant::now(),
+                Ok(Ok(false)) => break,
+                Ok(Err(err)) => warn!(error = %err, "renew leader lease failed"),
+                Err(_) => warn!("renew leader lease timed out"),
+            }
+            if renewed.elapsed() > self.renew_deadline {
+                break;
+            }
+        }
+        error!(identity = %self.identity, "lost the leader lease");
+        self.metrics.set_leader(false);
+    }
+
+    /// Gives the Lease up, so another replica takes over without waiting for it to expire.
+    pub async fn release(&self) {
+        self.metrics.set_leader(false);
+        let Ok(Some(mut lease)) = self.api.get_opt(LEASE_NAME).await else {
+            return;
+        };
+        let Some(spec) = lease.spec.as_mut().filter(|s| self.holds(s)) else {
+            return;
+        };
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.renew_time = Some(MicroTime(Utc::now()));
+        match self.api.replace(LEASE_NAME, &PostParams::default(), &lease).await {
+            Ok(_) => info!(identity = %self.identity, "released the leader lease"),
+            Err(err) => warn!(error = %err, "release leader lease failed"),
+        }
+    }
+
+    /// Takes or renews the Lease. Returns whether this replica holds it.
+    async fn try_acquire_or_renew(&self) -> Result<bool, kube::Error> {
+        let now = Utc::now();
+        let Some(lease) = self.api.get_opt(LEASE_NAME).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta { name: Some(LEASE_NAME.to_string()), ..Default::default() },
+                spec: Some(self.lease_spec(now, now, 0)),
+            };
+            return conflict_as_false(self.api.create(&PostParams::default(), &lease).await);
+        };
+        let spec = lease.spec.unwrap_or_default();
+        let transitions = spec.lease_transitions.unwrap_or_default();
+        let spec = if self.holds(&spec) {
+            let acquired = spec.acquire_time.map_or(now, |t| t.0);
+            self.lease_spec(acquired, now, transitions)
+        } else if is_expired(&spec, now) {
+            self.lease_spec(now, now, transitions + 1)
+        } else {
+            return Ok(false);
+        };
+        // The resource version in the metadata makes the replace fail if another replica got there first.
+        let lease = Lease { metadata: lease.metadata, spec: Some(spec) };
+        conflict_as_false(self.api.replace(LEASE_NAME, &PostParams::default(), &lease).await)
+    }
+
+    fn holds(&self, spec: &LeaseSpec) -> bool {
+        spec.holder_identity.as_deref() == Some(self.identity.as_str())
+    }
+
+    fn lease_spec(
+        &self,
+        acquired: DateTime<Utc>,
+        renewed: DateTime<Utc>,
+        transitions: i32,
+    ) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+            acquire_time: Some(MicroTime(acquired)),
+            renew_time: Some(MicroTime(renewed)),
+            lease_transitions: Some(transitions),
+        }
+    }
+}
+
+/// Whether the holder of the Lease, if any, stopped renewing it for longer than its duration.
+fn is_expired(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    let (Some(_), Some(renewed), Some(duration)) =
+        (&spec.holder_identity, &spec.renew_time, spec.lease_duration_seconds)
+    else {
+        return true;
+    };
+    renewed.0 + k8s_openapi::chrono::Duration::seconds(duration.into()) < now
+}
+
+fn conflict_as_false<T>(res: Result<T, kube::Error>) -> Result<bool, kube::Error> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn env_secs(name: &str, default: Duration) -> Result<Duration, Error> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+    let secs = value.trim().parse::<u64>().ok().filter(|s| *s > 0);
+    secs.map(Duration::from_secs)
+        .ok_or_else(|| Error::InvalidLeaderElection(format!("{}={:?}", name, value)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_expired;
+    use k8s_openapi::api::coordination::v1::LeaseSpec;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+    use k8s_openapi::chrono::{Duration, Utc};
+
+    #[test]
+    fn test_is_expired() {
+        let now = Utc::now();
+        let mut spec = LeaseSpec {
+            holder_identity: Some("autoscaler-genie-0".to_string()),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(now - Duration::seconds(5))),
+            ..Default::default()
+        };
+        assert!(!is_expired(&spec, now));
+
+        spec.renew_time = Some(MicroTime(now - Duration::seconds(20)));
+        assert!(is_expired(&spec, now));
+
+        spec.renew_time = Some(MicroTime(now));
+        spec.holder_identity = None;
+        assert!(is_expired(&spec, now));
+    }
+}
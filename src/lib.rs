@@ -2,3 +2,14 @@ pub mod vpa;
 mod controller;
 pub use controller::*;
 mod utils;
+mod overrides;
+mod events;
+mod backoff;
+mod leader;
+mod metrics;
+mod http;
+mod health;
+mod diagnostics;
+mod config;
+#[cfg(feature = "telemetry")]
+mod telemetry;
@@ -1,5 +1,142 @@
+use autoscaler_genie::{LogFormat, Options};
+use clap::{Args, FromArgMatches, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Runs the controller when no subcommand is given.
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    global: GlobalArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the controller.
+    Run(RunArgs),
+    /// Prints the AutoVPA and ClusterAutoVPA CRDs.
+    Crd,
+    /// Prints the changes every AutoVPA would make to the VPAs, without making them.
+    Plan,
+    /// Prints the version.
+    Version,
+}
+
+#[derive(Args)]
+struct GlobalArgs {
+    /// Kubeconfig to connect with, rather than the in-cluster config or the default kubeconfig.
+    #[arg(long, global = true, env = "AUTOVPA_KUBECONFIG")]
+    kubeconfig: Option<PathBuf>,
+    /// Context of the kubeconfig to connect with, rather than its current context.
+    #[arg(long, global = true, env = "AUTOVPA_CONTEXT")]
+    context: Option<String>,
+    /// Config file of the controller, reloaded when it changes.
+    #[arg(long, global = true, env = "AUTOVPA_CONFIG")]
+    config: Option<PathBuf>,
+    /// Format of the logs: compact, pretty or json.
+    #[arg(long, global = true, env = "AUTOVPA_LOG_FORMAT", default_value = "compact")]
+    log_format: LogFormat,
+    /// Field manager of the applied VPAs.
+    #[arg(long, global = true, env = "AUTOVPA_FIELD_MANAGER", default_value = "autovpa.dev")]
+    field_manager: String,
+    /// Comma-separated namespaces to watch, rather than every namespace. ClusterAutoVPAs are then ignored.
+    #[arg(long, global = true, env = "AUTOVPA_WATCH_NAMESPACES", value_delimiter = ',')]
+    watch_namespaces: Vec<String>,
+}
+
+#[derive(Args, Default)]
+struct RunArgs {
+    /// Dry-runs every AutoVPA, as if they all had `dryRun: true`.
+    #[arg(long, env = "AUTOVPA_DRY_RUN")]
+    dry_run: bool,
+    /// Reconciles running at once at most, unbounded if 0.
+    #[arg(long, env = "AUTOVPA_CONCURRENCY", default_value_t = 0)]
+    concurrency: usize,
+    /// Address the metrics, the probes and the diagnostics are served on.
+    #[arg(long, env = "AUTOVPA_METRICS_ADDRESS", default_value = "0.0.0.0:8080")]
+    metrics_address: String,
+}
+
+impl RunArgs {
+    /// The flags of `run` when it is implied by the lack of a subcommand, only set through their env vars.
+    fn from_env() -> Result<Self, clap::Error> {
+        let command = Self::augment_args(clap::Command::new("run"));
+        Self::from_arg_matches(&command.try_get_matches_from(["run"])?)
+    }
+}
+
+impl GlobalArgs {
+    fn options(self, run: RunArgs) -> Options {
+        Options {
+            kubeconfig: self.kubeconfig,
+            context: self.context,
+            config: self.config,
+            dry_run: run.dry_run,
+            field_manager: self.field_manager,
+            concurrency: run.concurrency,
+            watch_namespaces: self.watch_namespaces,
+            metrics_address: run.metrics_address,
+        }
+    }
+}
+
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
-    autoscaler_genie::run().await?;
+    let Cli { command, global } = Cli::parse();
+    let command = match command {
+        Some(command) => command,
+        None => Command::Run(RunArgs::from_env().unwrap_or_else(|err| err.exit())),
+    };
+    match command {
+        Command::Run(run) => {
+            autoscaler_genie::init_logging(global.log_format)?;
+            autoscaler_genie::run(&global.options(run)).await?;
+        }
+        Command::Crd => print!("{}", autoscaler_genie::crds()),
+        Command::Plan => {
+            autoscaler_genie::init_logging(global.log_format)?;
+            let plans = autoscaler_genie::plan(&global.options(RunArgs::default())).await?;
+            print!("{}", serde_yaml::to_string(&plans)?);
+            if plans.iter().any(|plan| plan.error.is_some()) {
+                anyhow::bail!("some AutoVPAs could not be planned");
+            }
+        }
+        Command::Version => println!("autoscaler-genie {}", env!("CARGO_PKG_VERSION")),
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Cli, Command, RunArgs};
+    use clap::Parser;
+
+    #[test]
+    fn test_default_run() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["autoscaler-genie"], args].concat());
+        let run = |args: &[&str]| match parse(args).unwrap().command {
+            Some(Command::Run(run)) => Some((run.dry_run, run.concurrency)),
+            _ => None,
+        };
+        assert_eq!(run(&["run", "--dry-run"]), Some((true, 0)));
+        assert_eq!(run(&["--kubeconfig", "config", "run", "--concurrency", "2"]), Some((false, 2)));
+        assert!(parse(&[]).unwrap().command.is_none());
+        assert!(matches!(
+            parse(&["--kubeconfig", "config", "crd"]).unwrap().command,
+            Some(Command::Crd)
+        ));
+
+        // The flags of `run` only follow `run`, and are neither taken nor checked by the other subcommands.
+        assert!(parse(&["--dry-run"]).is_err());
+        assert!(parse(&["crd", "--concurrency", "2"]).is_err());
+        std::env::set_var("AUTOVPA_DRY_RUN", "maybe");
+        assert!(matches!(parse(&["version"]).unwrap().command, Some(Command::Version)));
+        assert!(parse(&["run"]).is_err());
+        assert!(RunArgs::from_env().is_err());
+        std::env::set_var("AUTOVPA_DRY_RUN", "true");
+        assert!(RunArgs::from_env().unwrap().dry_run);
+        std::env::remove_var("AUTOVPA_DRY_RUN");
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bounds in seconds of the reconcile duration histogram buckets.
+const DURATION_BUCKETS: [f64; 9] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// VPA counts by target kind and namespace.
+pub type VpaCounts = BTreeMap<(String, String), usize>;
+
+/// Metrics of the controller, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    leader: AtomicBool,
+    reconciles: Mutex<BTreeMap<String, Reconciles>>,
+    /// VPAs managed by each AutoVPA, by target kind and namespace.
+    managed_vpas: Mutex<BTreeMap<String, VpaCounts>>,
+    in_flight: AtomicI64,
+    /// Reconciles waiting for a free slot of `concurrency`, not the ones the controller has yet to schedule.
+    waiting_for_slot: AtomicI64,
+    retries_pending: AtomicI64,
+    /// Relists and errors of the watches, by watched kind.
+    watch_restarts: Mutex<BTreeMap<String, u64>>,
+    watch_errors: Mutex<BTreeMap<String, u64>>,
+    controller_restarts: AtomicU64,
+}
+
+#[derive(Default)]
+struct Reconciles {
+    count: u64,
+    failures: u64,
+    buckets: [u64; DURATION_BUCKETS.len()],
+    seconds: f64,
+}
+
+impl Metrics {
+    /// Records whether this replica holds the leader lease, and so runs the controller.
+    pub fn set_leader(&self, leader: bool) {
+        self.leader.store(leader, Ordering::Relaxed);
+    }
+
+    /// Counts a reconcile of `autovpa`, whose duration is recorded once the returned guard is dropped.
+    pub fn measure_reconcile(&self, autovpa: String) -> ReconcileMeasurer<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        ReconcileMeasurer { metrics: self, autovpa, start: Instant::now() }
+    }
+
+    /// Counts a reconcile waiting for a free slot of `concurrency`, until the returned guard is dropped.
+    pub fn wait_for_slot(&self) -> WaitingReconcile<'_> {
+        self.waiting_for_slot.fetch_add(1, Ordering::Relaxed);
+        WaitingReconcile { metrics: self }
+    }
+
+    pub fn reconcile_failed(&self, autovpa: String) {
+        self.reconciles.lock().unwrap().entry(autovpa).or_default().failures += 1;
+    }
+
+    /// Replaces the counts of the VPAs managed by `autovpa`, by target kind and namespace.
+    pub fn set_managed_vpas(&self, autovpa: String, vpas: VpaCounts) {
+        self.managed_vpas.lock().unwrap().insert(autovpa, vpas);
+    }
+
+    /// Drops the metrics of the AutoVPAs that no longer exist.
+    pub fn retain_autovpas(&self, exists: impl Fn(&str) -> bool) {
+        self.reconciles.lock().unwrap().retain(|autovpa, _| exists(autovpa));
+        self.managed_vpas.lock().unwrap().retain(|autovpa, _| exists(autovpa));
+    }
+
+    /// Records the AutoVPAs waiting for a retry after a failed reconcile.
+    pub fn set_retries_pending(&self, pending: usize) {
+        self.retries_pending.store(pending as i64, Ordering::Relaxed);
+    }
+
+    /// Counts a relist of a watch of `kind` after its initial list, as when its resource version expired.
+    pub fn watch_restarted(&self, kind: &str) {
+        *self.watch_restarts.lock().unwrap().entry(kind.to_string()).or_default() += 1;
+    }
+
+    /// Counts an error of a watch of `kind`, which is retried.
+    pub fn watch_failed(&self, kind: &str) {
+        *self.watch_errors.lock().unwrap().entry(kind.to_string()).or_default() += 1;
+    }
+
+    /// Counts a restart of the controllers, when the set of watched target kinds changes.
+    pub fn controller_restarted(&self) {
+        self.controller_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(&mut out, "autoscaler_genie_leader", "gauge", "Whether this replica is the leader.");
+        sample(&mut out, "autoscaler_genie_leader", &[], self.leader.load(Ordering::Relaxed) as u8);
+
+        let reconciles = self.reconciles.lock().unwrap();
+        let name = "autoscaler_genie_reconciles_total";
+        header(&mut out, name, "counter", "Reconciles of each AutoVPA.");
+        for (autovpa, r) in reconciles.iter() {
+            sample(&mut out, name, &[("autovpa", autovpa)], r.count);
+        }
+        let name = "autoscaler_genie_reconcile_failures_total";
+        header(&mut out, name, "counter", "Failed reconciles of each AutoVPA.");
+        for (autovpa, r) in reconciles.iter() {
+            sample(&mut out, name, &[("autovpa", autovpa)], r.failures);
+        }
+        let name = "autoscaler_genie_reconcile_duration_seconds";
+        header(&mut out, name, "histogram", "Duration of the reconciles of each AutoVPA.");
+        for (autovpa, r) in reconciles.iter() {
+            let bucket = format!("{}_bucket", name);
+            for (le, count) in DURATION_BUCKETS.iter().zip(r.buckets) {
+                sample(&mut out, &bucket, &[("autovpa", autovpa), ("le", &le.to_string())], count);
+            }
+            sample(&mut out, &bucket, &[("autovpa", autovpa), ("le", "+Inf")], r.count);
+            sample(&mut out, &format!("{}_sum", name), &[("autovpa", autovpa)], r.seconds);
+            sample(&mut out, &format!("{}_count", name), &[("autovpa", autovpa)], r.count);
+        }
+        drop(reconciles);
+
+        let mut managed = VpaCounts::new();
+        for vpas in self.managed_vpas.lock().unwrap().values() {
+            for (key, count) in vpas {
+                *managed.entry(key.clone()).or_default() += count;
+            }
+        }
+        let name = "autoscaler_genie_managed_vpas";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "VPAs managed by the controller, by target kind and namespace.",
+        );
+        for ((kind, namespace), count) in managed {
+            sample(&mut out, name, &[("kind", &kind), ("namespace", &namespace)], count);
+        }
+
+        let name = "autoscaler_genie_reconciles_in_flight";
+        header(&mut out, name, "gauge", "Reconciles currently running.");
+        sample(&mut out, name, &[], self.in_flight.load(Ordering::Relaxed));
+        let name = "autoscaler_genie_reconciles_waiting_for_slot";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Reconciles waiting for a free slot of the concurrency limit, not the ones yet to be scheduled.",
+        );
+        sample(&mut out, name, &[], self.waiting_for_slot.load(Ordering::Relaxed));
+        let name = "autoscaler_genie_retries_pending";
+        header(&mut out, name, "gauge", "AutoVPAs waiting for a retry after a failed reconcile.");
+        sample(&mut out, name, &[], self.retries_pending.load(Ordering::Relaxed));
+        let name = "autoscaler_genie_watch_restarts_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Relists of the watches after their initial list, by watched kind.",
+        );
+        for (kind, count) in self.watch_restarts.lock().unwrap().iter() {
+            sample(&mut out, name, &[("kind", kind)], count);
+        }
+        let name = "autoscaler_genie_watch_errors_total";
+        header(&mut out, name, "counter", "Errors of the watches, by watched kind.");
+        for (kind, count) in self.watch_errors.lock().unwrap().iter() {
+            sample(&mut out, name, &[("kind", kind)], count);
+        }
+        let name = "autoscaler_genie_controller_restarts_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Restarts of the controllers when the set of targeted kinds changes.",
+        );
+        sample(&mut out, name, &[], self.controller_restarts.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Records the duration of a reconcile when dropped.
+pub struct ReconcileMeasurer<'a> {
+    metrics: &'a Metrics,
+    autovpa: String,
+    start: Instant,
+}
+
+impl Drop for ReconcileMeasurer<'_> {
+    fn drop(&mut self) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let mut reconciles = self.metrics.reconciles.lock().unwrap();
+        let r = reconciles.entry(std::mem::take(&mut self.autovpa)).or_default();
+        r.count += 1;
+        r.seconds += seconds;
+        for (le, count) in DURATION_BUCKETS.iter().zip(r.buckets.iter_mut()) {
+            if seconds <= *le {
+                *count += 1;
+            }
+        }
+    }
+}
+
+/// Records that a reconcile no longer waits for a free slot when dropped.
+pub struct WaitingReconcile<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for WaitingReconcile<'_> {
+    fn drop(&mut self) {
+        self.metrics.waiting_for_slot.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, type_: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, type_);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    let _ = if labels.is_empty() {
+        writeln!(out, "{} {}", name, value)
+    } else {
+        writeln!(out, "{}{{{}}} {}", name, labels.join(","), value)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        assert!(metrics.render().contains("\nautoscaler_genie_leader 0\n"));
+        metrics.set_leader(true);
+        assert!(metrics.render().contains("\nautoscaler_genie_leader 1\n"));
+    }
+
+    #[test]
+    fn test_reconcile_metrics() {
+        let metrics = Metrics::default();
+        drop(metrics.measure_reconcile("AutoVPA/default/nginx".to_string()));
+        metrics.reconcile_failed("AutoVPA/default/nginx".to_string());
+        let vpas = BTreeMap::from([(("Deployment".to_string(), "default".to_string()), 2)]);
+        metrics.set_managed_vpas("AutoVPA/default/nginx".to_string(), vpas.clone());
+        metrics.set_managed_vpas("ClusterAutoVPA/all".to_string(), vpas);
+
+        let rendered = metrics.render();
+        assert!(rendered
+            .contains("autoscaler_genie_reconciles_total{autovpa=\"AutoVPA/default/nginx\"} 1\n"));
+        assert!(rendered.contains(
+            "autoscaler_genie_reconcile_failures_total{autovpa=\"AutoVPA/default/nginx\"} 1\n"
+        ));
+        assert!(rendered.contains("le=\"+Inf\"} 1\n"));
+        assert!(rendered.contains(
+            "autoscaler_genie_managed_vpas{kind=\"Deployment\",namespace=\"default\"} 4\n"
+        ));
+        assert!(rendered.contains("autoscaler_genie_reconciles_in_flight 0\n"));
+        let waiting = metrics.wait_for_slot();
+        assert!(metrics.render().contains("autoscaler_genie_reconciles_waiting_for_slot 1\n"));
+        drop(waiting);
+        assert!(metrics.render().contains("autoscaler_genie_reconciles_waiting_for_slot 0\n"));
+
+        metrics.retain_autovpas(|autovpa| autovpa.starts_with("ClusterAutoVPA/"));
+        let rendered = metrics.render();
+        assert!(!rendered.contains("AutoVPA/default/nginx"));
+        assert!(rendered.contains(
+            "autoscaler_genie_managed_vpas{kind=\"Deployment\",namespace=\"default\"} 2\n"
+        ));
+    }
+
+    #[test]
+    fn test_watch_metrics() {
+        let metrics = Metrics::default();
+        metrics.watch_restarted("Deployment");
+        metrics.watch_restarted("Deployment");
+        metrics.watch_failed("AutoVPA");
+        metrics.controller_restarted();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("autoscaler_genie_watch_restarts_total{kind=\"Deployment\"} 2\n"));
+        assert!(!rendered.contains("autoscaler_genie_watch_restarts_total{kind=\"AutoVPA\"}"));
+        assert!(rendered.contains("autoscaler_genie_watch_errors_total{kind=\"AutoVPA\"} 1\n"));
+        assert!(rendered.contains("autoscaler_genie_controller_restarts_total 1\n"));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::controller::Error;
+use crate::vpa::{
+    ContainerControlledValues, ContainerPolicies, VerticalPodAutoscalerResourcePolicy,
+    VerticalPodAutoscalerSpec, VerticalPodAutoscalerUpdateMode,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use std::collections::BTreeMap;
+
+/// Workload annotations of this prefix override the VPA template of the AutoVPA selecting the workload.
+pub const OVERRIDE_PREFIX: &str = "autovpa.dev/";
+
+/// Workloads with this annotation set to `"true"` get no VPA from any AutoVPA.
+pub const IGNORE_ANNOTATION: &str = "autovpa.dev/ignore";
+
+const IGNORE: &str = "ignore";
+const UPDATE_MODE: &str = "update-mode";
+const MIN_REPLICAS: &str = "min-replicas";
+const CONTROLLED_RESOURCES: &str = "controlled-resources";
+const CONTROLLED_VALUES: &str = "controlled-values";
+const MIN_CPU: &str = "min-cpu";
+const MIN_MEMORY: &str = "min-memory";
+const MAX_CPU: &str = "max-cpu";
+const MAX_MEMORY: &str = "max-memory";
+
+/// Layers the `autovpa.dev/*` annotations of a workload over the rendered VPA `spec`.
+/// Container level settings apply to every container policy of the template, or to a new `*` policy if it has none.
+/// All invalid annotations are reported at once, and `spec` is left untouched in that case.
+pub fn apply_overrides(
+    spec: &mut VerticalPodAutoscalerSpec,
+    annotations: &BTreeMap<String, String>,
+) -> Result<(), Error> {
+    let mut overridden = spec.clone();
+    let mut errors = vec![];
+    for (key, value) in annotations {
+        let Some(name) = key.strip_prefix(OVERRIDE_PREFIX) else {
+            continue;
+        };
+        if let Err(err) = apply_override(&mut overridden, name, value.trim()) {
+            errors.push(format!("{key}={value:?}: {err}"));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::InvalidOverride(errors.join("; ")));
+    }
+    *spec = overridden;
+    Ok(())
+}
+
+fn apply_override(
+    spec: &mut VerticalPodAutoscalerSpec,
+    name: &str,
+    value: &str,
+) -> Result<(), String> {
+    match name {
+        IGNORE => {
+            if value != "true" && value != "false" {
+                return Err("expected true or false".into());
+            }
+        }
+        UPDATE_MODE => {
+            let mode = match value {
+                "Off" => VerticalPodAutoscalerUpdateMode::Off,
+                "Initial" => VerticalPodAutoscalerUpdateMode::Initial,
+                "Recreate" => VerticalPodAutoscalerUpdateMode::Recreate,
+                "Auto" => VerticalPodAutoscalerUpdateMode::Auto,
+                _ => return Err("expected one of Off, Initial, Recreate, Auto".into()),
+            };
+            spec.update_policy.get_or_insert_with(Default::default).update_mode = Some(mode);
+        }
+        MIN_REPLICAS => {
+            let replicas = value.parse::<i32>().ok().filter(|r| *r > 0);
+            let replicas = replicas.ok_or("expected a positive integer")?;
+            spec.update_policy.get_or_insert_with(Default::default).min_replicas = Some(replicas);
+        }
+        CONTROLLED_RESOURCES => {
+            let resources: Vec<String> = value.split(',').map(|r| r.trim().to_string()).collect();
+            if resources.iter().any(|r| r != "cpu" && r != "memory") {
+                return Err("expected a comma separated list of cpu, memory".into());
+            }
+            container_policies(spec).for_each(|p| p.controlled_resources = Some(resources.clone()));
+        }
+        CONTROLLED_VALUES => {
+            let values = match value {
+                "RequestsAndLimits" => ContainerControlledValues::RequestsAndLimits,
+                "RequestsOnly" => ContainerControlledValues::RequestsOnly,
+                _ => return Err("expected one of RequestsAndLimits, RequestsOnly".into()),
+            };
+            container_policies(spec).for_each(|p| p.controlled_values = Some(values.clone()));
+        }
+        MIN_CPU | MIN_MEMORY | MAX_CPU | MAX_MEMORY => {
+            if !is_quantity(value) {
+                return Err("expected a resource quantity, e.g. 500m or 4Gi".into());
+            }
+            let (bound, resource) = name.split_once('-').unwrap_or_default();
+            for policy in container_policies(spec) {
+                let allowed = match bound {
+                    "min" => &mut policy.min_allowed,
+                    _ => &mut policy.max_allowed,
+                };
+                allowed
+                    .get_or_insert_with(Default::default)
+                    .insert(resource.to_string(), Quantity(value.to_string()));
+            }
+        }
+        _ => return Err("unknown override annotation".into()),
+    }
+    Ok(())
+}
+
+fn container_policies(
+    spec: &mut VerticalPodAutoscalerSpec,
+) -> impl Iterator<Item = &mut ContainerPolicies> {
+    let policies = spec
+        .resource_policy
+        .get_or_insert(VerticalPodAutoscalerResourcePolicy { container_policies: None })
+        .container_policies
+        .get_or_insert_with(Vec::new);
+    if policies.is_empty() {
+        policies.push(ContainerPolicies {
+            container_name: Some("*".to_string()),
+            controlled_resources: None,
+            controlled_values: None,
+            max_allowed: None,
+            min_allowed: None,
+            mode: None,
+        });
+    }
+    policies.iter_mut()
+}
+
+// Refer to: https://github.com/kubernetes/apimachinery/blob/master/pkg/api/resource/quantity.go
+fn is_quantity(value: &str) -> bool {
+    let value = value.strip_prefix(['+', '-']).unwrap_or(value);
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    if number.is_empty() || number == "." || number.matches('.').count() > 1 {
+        return false;
+    }
+    match suffix {
+        "" | "m" | "k" | "M" | "G" | "T" | "P" | "E" | "Ki" | "Mi" | "Gi" | "Ti" | "Pi" | "Ei" => {
+            true
+        }
+        exp => exp
+            .strip_prefix(['e', 'E'])
+            .map(|e| e.strip_prefix(['+', '-']).unwrap_or(e))
+            .is_some_and(|e| !e.is_empty() && e.chars().all(|c| c.is_ascii_digit())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_overrides, is_quantity};
+    use crate::vpa::{VerticalPodAutoscalerSpec, VerticalPodAutoscalerUpdateMode};
+    use std::collections::BTreeMap;
+
+    fn spec() -> VerticalPodAutoscalerSpec {
+        serde_yaml::from_str(
+            r#"
+            resourcePolicy:
+              containerPolicies:
+              - containerName: "*"
+                maxAllowed:
+                  cpu: "2"
+                  memory: 2Gi
+            updatePolicy:
+              updateMode: Auto
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut spec = spec();
+        let annotations = BTreeMap::from([
+            ("autovpa.dev/update-mode".to_string(), "Off".to_string()),
+            ("autovpa.dev/max-memory".to_string(), "4Gi".to_string()),
+            ("autovpa.dev/controlled-resources".to_string(), "memory".to_string()),
+            ("app.kubernetes.io/name".to_string(), "nginx".to_string()),
+        ]);
+        apply_overrides(&mut spec, &annotations).unwrap();
+
+        let update_mode = spec.update_policy.as_ref().and_then(|p| p.update_mode.clone());
+        assert_eq!(update_mode, Some(VerticalPodAutoscalerUpdateMode::Off));
+        let policy = &spec.resource_policy.unwrap().container_policies.unwrap()[0];
+        let max_allowed = policy.max_allowed.as_ref().unwrap();
+        assert_eq!(max_allowed["memory"].0, "4Gi");
+        assert_eq!(max_allowed["cpu"].0, "2");
+        assert_eq!(policy.controlled_resources, Some(vec!["memory".to_string()]));
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let mut spec = spec();
+        let annotations = BTreeMap::from([
+            ("autovpa.dev/update-mode".to_string(), "Sometimes".to_string()),
+            ("autovpa.dev/max-memory".to_string(), "4 GB".to_string()),
+            ("autovpa.dev/min-cpu".to_string(), "100m".to_string()),
+        ]);
+        let err = apply_overrides(&mut spec, &annotations).unwrap_err().to_string();
+        assert!(err.contains("autovpa.dev/update-mode"));
+        assert!(err.contains("autovpa.dev/max-memory"));
+        assert!(!err.contains("autovpa.dev/min-cpu"));
+        assert_eq!(spec, self::spec());
+    }
+
+    #[test]
+    fn test_is_quantity() {
+        for q in ["1", "0.5", "500m", "4Gi", "1e3", "+2", "1.5E-2"] {
+            assert!(is_quantity(q), "{q}");
+        }
+        for q in ["", "Gi", "4 GB", "1..2", "1e", "4gi"] {
+            assert!(!is_quantity(q), "{q}");
+        }
+    }
+}
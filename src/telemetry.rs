@@ -0,0 +1,127 @@
+use opentelemetry::sdk::trace::{self, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{TraceContextExt, TraceError, TraceId};
+use opentelemetry::{Key, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Service name of the traces, unless set through `OTEL_SERVICE_NAME` or `OTEL_RESOURCE_ATTRIBUTES`.
+const SERVICE_NAME: &str = "autoscaler-genie";
+
+/// Exports the spans to the OTLP/HTTP collector configured through the `OTEL_*` environment variables.
+/// Nothing is exported unless `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set.
+pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, Tracer>>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let env = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+    if env("OTEL_SDK_DISABLED").as_deref() == Some("true")
+        || env("OTEL_TRACES_EXPORTER").as_deref() == Some("none")
+    {
+        return Ok(None);
+    }
+    let Some(endpoint) = traces_endpoint(
+        env("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"),
+        env("OTEL_EXPORTER_OTLP_ENDPOINT"),
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer(endpoint)?)))
+}
+
+/// The URL the spans are posted to: the traces endpoint as is, or the path of the traces under the base endpoint.
+fn traces_endpoint(traces: Option<String>, base: Option<String>) -> Option<String> {
+    traces.or_else(|| Some(format!("{}/v1/traces", base?.trim_end_matches('/'))))
+}
+
+/// Batches the spans to `endpoint`, sampled and described as set through the `OTEL_*` environment variables.
+fn tracer(endpoint: String) -> Result<Tracer, TraceError> {
+    let resource = Resource::default();
+    let resource = match resource.get(Key::new("service.name")) {
+        Some(name) if name.as_str() != "unknown_service" => resource,
+        _ => resource.merge(&Resource::new([KeyValue::new("service.name", SERVICE_NAME)])),
+    };
+    let exporter = opentelemetry_otlp::new_exporter().http().with_env().with_endpoint(endpoint);
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(opentelemetry::runtime::Tokio)
+}
+
+/// The trace ID of the current span, if it is exported.
+pub fn trace_id() -> Option<TraceId> {
+    let trace_id = tracing::Span::current().context().span().span_context().trace_id();
+    (trace_id != TraceId::INVALID).then_some(trace_id)
+}
+
+/// Exports the spans still batched, before the controller exits.
+pub async fn shutdown() {
+    // Shutting the batch processor down blocks until it is flushed by the runtime.
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{trace_id, tracer, traces_endpoint};
+    use actix_web::web::{Bytes, Data};
+    use actix_web::{post, App, HttpResponse, HttpServer, Responder};
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_traces_endpoint() {
+        let endpoint = |traces: Option<&str>, base: Option<&str>| {
+            traces_endpoint(traces.map(Into::into), base.map(Into::into))
+        };
+        assert_eq!(endpoint(None, None), None);
+        assert_eq!(
+            endpoint(None, Some("http://tempo:4318/")).as_deref(),
+            Some("http://tempo:4318/v1/traces")
+        );
+        assert_eq!(
+            endpoint(Some("http://collector/traces"), Some("http://tempo:4318")).as_deref(),
+            Some("http://collector/traces")
+        );
+    }
+
+    type Exports = Arc<Mutex<Vec<Bytes>>>;
+
+    #[post("/v1/traces")]
+    async fn collect(exports: Data<Exports>, body: Bytes) -> impl Responder {
+        exports.lock().unwrap().push(body);
+        HttpResponse::Ok().finish()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export() {
+        // A stand-in for the collector, which keeps the protobuf export requests.
+        let exports = Exports::default();
+        let data = Data::new(exports.clone());
+        let server = HttpServer::new(move || App::new().app_data(data.clone()).service(collect))
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        tokio::spawn(server.run());
+
+        let tracer = tracer(format!("http://{}/v1/traces", addr)).unwrap();
+        let provider = tracer.provider().unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let traced = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("reconcile", autovpa = "ClusterAutoVPA/office");
+            span.in_scope(trace_id)
+        });
+        assert!(traced.is_some());
+        tokio::task::spawn_blocking(move || provider.force_flush()).await.unwrap();
+
+        let exports = exports.lock().unwrap();
+        assert_eq!(exports.len(), 1);
+        let body = String::from_utf8_lossy(&exports[0]);
+        assert!(body.contains("reconcile"));
+        assert!(body.contains("ClusterAutoVPA/office"));
+        assert!(body.contains("autoscaler-genie"));
+    }
+}
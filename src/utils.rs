@@ -1,8 +1,94 @@
 use crate::controller::Error;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::core::GroupVersionKind;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 use tracing::error;
 
+/// Max length of a DNS subdomain name, which object names must be.
+const MAX_NAME_LEN: usize = 253;
+/// Length of the hash suffix appended to names that had to be truncated.
+const NAME_HASH_LEN: usize = 8;
+
+/// Checks that a name template has the `{name}` placeholder, without which all the targets would get the same VPA.
+pub fn validate_name_template(template: &str) -> Result<(), Error> {
+    if !template.contains("{name}") {
+        return Err(Error::InvalidNameTemplate(format!("{template}: {{name}} is missing")));
+    }
+    Ok(())
+}
+
+/// Renders a VPA name from a template with `{kind}`, `{namespace}` and `{name}` placeholders.
+/// The result is lowercased with invalid characters replaced by `-`, and names longer than
+/// 253 characters are truncated with a hash of the target appended, so they stay unique.
+pub fn render_name(
+    template: &str,
+    kind: &str,
+    namespace: &str,
+    name: &str,
+) -> Result<String, Error> {
+    let rendered = template
+        .replace("{kind}", kind)
+        .replace("{namespace}", namespace)
+        .replace("{name}", name)
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '-' })
+        .collect::<String>();
+    let rendered = rendered.trim_matches(|c: char| c == '-' || c == '.');
+    if rendered.is_empty() {
+        return Err(Error::InvalidNameTemplate(format!(
+            "{template}: renders an empty name for {kind} {namespace}/{name}"
+        )));
+    }
+    if rendered.len() <= MAX_NAME_LEN {
+        return Ok(rendered.to_string());
+    }
+    // Hashing the target rather than the rendered name keeps targets apart even when they render alike.
+    let hash = hex_digest(format!("{kind}/{namespace}/{name}").as_bytes());
+    let prefix = rendered[..MAX_NAME_LEN - NAME_HASH_LEN - 1].trim_end_matches(['-', '.']);
+    Ok(format!("{}-{}", prefix, &hash[..NAME_HASH_LEN]))
+}
+
+/// Compiles a name pattern: a regular expression when prefixed with `regex:`, a glob otherwise.
+/// Globs match the whole name, `*` matches any sequence of characters and `?` a single one.
+pub fn compile_pattern(pattern: &str) -> Result<Regex, Error> {
+    let re = match pattern.strip_prefix("regex:") {
+        Some(re) => re.to_string(),
+        None => {
+            let glob: String = pattern
+                .chars()
+                .map(|c| match c {
+                    '*' => ".*".to_string(),
+                    '?' => ".".to_string(),
+                    c => regex::escape(&c.to_string()),
+                })
+                .collect();
+            format!("^{glob}$")
+        }
+    };
+    Regex::new(&re).map_err(|e| Error::InvalidPattern(format!("{pattern}: {e}")))
+}
+
+/// Parses `group/version/Kind`, or `version/Kind` for the core group.
+pub fn parse_gvk(gvk: &str) -> Result<GroupVersionKind, Error> {
+    match gvk.split('/').collect::<Vec<_>>()[..] {
+        [group, version, kind] if !version.is_empty() && !kind.is_empty() => {
+            Ok(GroupVersionKind::gvk(group, version, kind))
+        }
+        [version, kind] if !version.is_empty() && !kind.is_empty() => {
+            Ok(GroupVersionKind::gvk("", version, kind))
+        }
+        _ => Err(Error::InvalidKind(format!("{gvk}, expected group/version/Kind"))),
+    }
+}
+
+/// Hex encoded sha256 of `data`.
+pub fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
 // Refer to: LabelSelectorAsSelector: https://github.com/kubernetes/kubernetes/blob/master/vendor/k8s.io/apimachinery/pkg/apis/meta/v1/helpers.go#L34
 pub fn match_label(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
     if let Some(match_labels) = &selector.match_labels {
@@ -19,16 +105,16 @@ pub fn match_label(selector: &LabelSelector, labels: &BTreeMap<String, String>)
     }
     for exp in selector.match_expressions.iter().flatten() {
         let matched = match exp.operator.as_str() {
-            "IN" => labels
+            "In" => labels
                 .get(&exp.key)
-                .map_or(false, |key| exp.values.as_ref().map_or(false, |v| v.contains(key))),
+                .is_some_and(|key| exp.values.as_ref().is_some_and(|v| v.contains(key))),
             "NotIn" => labels
                 .get(&exp.key)
-                .map_or(true, |key| exp.values.as_ref().map_or(true, |v| !v.contains(key))),
+                .is_none_or(|key| exp.values.as_ref().is_none_or(|v| !v.contains(key))),
             "Exists" => labels.get(&exp.key).is_some(),
             "DoesNotExist" => labels.get(&exp.key).is_none(),
             op => {
-                error!("LabelSelector has invalid/unknown operator [{op}]");
+                error!(operator = op, "LabelSelector has an invalid or unknown operator");
                 false
             }
         };
@@ -126,10 +212,13 @@ pub fn convert_label_selector_to_query_string(
     Ok(query_string)
 }
 
+#[cfg(test)]
 mod test {
+    use crate::utils::{
+        compile_pattern, match_label, parse_gvk, render_name, validate_name_template,
+    };
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
     use std::collections::BTreeMap;
-    use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
-    use crate::utils::match_label;
 
     #[test]
     fn test_match_labels() {
@@ -142,5 +231,77 @@ mod test {
 
         let labels = BTreeMap::from([("app".to_string(), "ll".to_string())]);
         assert!(!match_label(&selector, &labels));
+
+        let selector = LabelSelector {
+            match_expressions: Some(vec![LabelSelectorRequirement {
+                key: "env".into(),
+                operator: "In".into(),
+                values: Some(vec!["prod".into(), "staging".into()]),
+            }]),
+            ..Default::default()
+        };
+        let labels = BTreeMap::from([("env".to_string(), "staging".to_string())]);
+        assert!(match_label(&selector, &labels));
+        let labels = BTreeMap::from([("env".to_string(), "dev".to_string())]);
+        assert!(!match_label(&selector, &labels));
+    }
+
+    #[test]
+    fn test_render_name() {
+        let render = |template, kind, namespace, name: &str| {
+            render_name(template, kind, namespace, name).unwrap()
+        };
+        assert_eq!(
+            render("{name}-{kind}-vpa", "StatefulSet", "default", "nginx"),
+            "nginx-statefulset-vpa"
+        );
+        assert_eq!(render("{namespace}_{name}", "Job", "Team-A", "x"), "team-a-x");
+
+        let long = "a".repeat(300);
+        let rendered = render("{name}-{kind}", "Deployment", "default", &long);
+        assert!(rendered.len() <= 253);
+        assert_eq!(rendered, render("{name}-{kind}", "Deployment", "default", &long));
+        assert_ne!(rendered, render("{name}-{kind}", "DaemonSet", "default", &long));
+        // Targets whose names truncate alike still get VPAs of their own.
+        assert_ne!(
+            render("{name}", "Deployment", "team-a", &long),
+            render("{name}", "Deployment", "team-b", &long)
+        );
+
+        assert!(render_name("---", "Job", "default", "x").is_err());
+    }
+
+    #[test]
+    fn test_validate_name_template() {
+        assert!(validate_name_template("{name}-{kind}-vpa").is_ok());
+        assert!(validate_name_template("shared").is_err());
+        assert!(validate_name_template("{namespace}-{kind}").is_err());
+    }
+
+    #[test]
+    fn test_compile_pattern() {
+        let glob = compile_pattern("team-*").unwrap();
+        assert!(glob.is_match("team-payments"));
+        assert!(!glob.is_match("my-team-payments"));
+        assert!(compile_pattern("api-v?").unwrap().is_match("api-v2"));
+        assert!(compile_pattern("a.b").unwrap().is_match("a.b"));
+        assert!(!compile_pattern("a.b").unwrap().is_match("axb"));
+
+        let re = compile_pattern("regex:^api-(v1|v2)$").unwrap();
+        assert!(re.is_match("api-v1"));
+        assert!(!re.is_match("api-v3"));
+        assert!(compile_pattern("regex:(").is_err());
+    }
+
+    #[test]
+    fn test_parse_gvk() {
+        let gvk = parse_gvk("argoproj.io/v1alpha1/Rollout").unwrap();
+        assert_eq!(
+            (gvk.group.as_str(), gvk.version.as_str(), gvk.kind.as_str()),
+            ("argoproj.io", "v1alpha1", "Rollout")
+        );
+        assert_eq!(parse_gvk("v1/ReplicationController").unwrap().group, "");
+        assert!(parse_gvk("Rollout").is_err());
+        assert!(parse_gvk("a/b/c/d").is_err());
     }
 }